@@ -0,0 +1,118 @@
+//! Measures how long assembling the chroot takes, which happens on every
+//! invocation.
+//!
+//! Besides the host's `/`, the chroot mirrors a synthetic tree of files,
+//! directories and symlinks into the nix store entry by entry. Each case
+//! also runs with serial mirroring as a baseline for the parallel one. Run
+//! with `cargo bench`; `BENCH_ITERATIONS` sets the number of runs per case.
+
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, ForkResult};
+use nix_user_chroot::{mkdtemp, RunChroot};
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+const DIRS: usize = 200;
+const FILES: usize = 1000;
+const SYMLINKS: usize = 500;
+
+/// Creates `DIRS` directories, `FILES` files and `SYMLINKS` symlinks into
+/// the nix store, like those of a profile, directly in `dir`.
+fn generate_tree(dir: &Path) {
+    for i in 0..DIRS {
+        let sub = dir.join(format!("dir-{}", i));
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("file"), "").unwrap();
+    }
+    for i in 0..FILES {
+        fs::write(dir.join(format!("file-{}", i)), "").unwrap();
+    }
+    for i in 0..SYMLINKS {
+        let target = format!("/nix/store/{:032}-package-{}/bin/program-{}", i, i, i);
+        symlink(target, dir.join(format!("link-{}", i))).unwrap();
+    }
+}
+
+/// Enters a fresh chroot in a forked child and returns how long it took,
+/// including the fork.
+fn time_enter(chroot: &RunChroot, nixdir: &Path) -> Duration {
+    let start = Instant::now();
+    // unsharing a user namespace requires a single-threaded process
+    let status = match unsafe { fork() }.expect("fork failed") {
+        ForkResult::Child => {
+            let ok = chroot.enter(nixdir).is_ok();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => waitpid(child, None).expect("waitpid failed"),
+    };
+    let elapsed = start.elapsed();
+    if !matches!(status, WaitStatus::Exited(_, 0)) {
+        eprintln!("entering the chroot failed: {:?}", status);
+        process::exit(1);
+    }
+    elapsed
+}
+
+fn report(name: &str, mut times: Vec<Duration>) {
+    times.sort();
+    println!(
+        "{:<32} min {:>10.2?}  median {:>10.2?}  max {:>10.2?}",
+        name,
+        times[0],
+        times[times.len() / 2],
+        times[times.len() - 1]
+    );
+}
+
+fn bench(name: &str, iterations: usize, nixdir: &Path, build: impl Fn(&Path) -> RunChroot) {
+    let mut enter = Vec::new();
+    let mut plan = Vec::new();
+    for _ in 0..iterations {
+        let rootdir = mkdtemp::mkdtemp("nix-chroot.XXXXXX", None).unwrap();
+        let chroot = build(&rootdir);
+
+        let start = Instant::now();
+        chroot.plan(nixdir).unwrap();
+        plan.push(start.elapsed());
+        enter.push(time_enter(&chroot, nixdir));
+
+        // the mounts are gone with the child, only the mount points remain
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+    report(&format!("{} (plan)", name), plan);
+    report(&format!("{} (enter)", name), enter);
+}
+
+fn main() {
+    let iterations = env::var("BENCH_ITERATIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(10);
+
+    let nixdir = mkdtemp::mkdtemp("nix.XXXXXX", None).unwrap();
+    fs::create_dir(nixdir.join("store")).unwrap();
+    let tree = mkdtemp::mkdtemp("tree.XXXXXX", None).unwrap();
+    generate_tree(&tree);
+
+    bench("host /", iterations, &nixdir, RunChroot::new);
+    // the baseline for mirroring the top-level entries in parallel
+    bench("host / (serial)", iterations, &nixdir, |rootdir| {
+        RunChroot::new(rootdir).mirror_threads(1)
+    });
+    // creating the tree's path inside makes the mirroring recurse into it
+    // instead of bind mounting it as a whole
+    bench("host / and tree", iterations, &nixdir, |rootdir| {
+        RunChroot::new(rootdir).mkdir(&tree)
+    });
+    bench("host / and tree (serial)", iterations, &nixdir, |rootdir| {
+        RunChroot::new(rootdir).mkdir(&tree).mirror_threads(1)
+    });
+
+    fs::remove_dir_all(&tree).unwrap();
+    fs::remove_dir_all(&nixdir).unwrap();
+}
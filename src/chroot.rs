@@ -0,0 +1,2730 @@
+use crate::helper::Helper;
+use crate::idmap::{self, MapHelper};
+use crate::mkdtemp::mkdtemp;
+use crate::signals;
+use crate::warn;
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+use nix::sys::stat::{umask, Mode};
+use nix::sys::statfs::{statfs, PROC_SUPER_MAGIC, SYSFS_MAGIC};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, fork, ForkResult, Gid, Uid};
+use std::collections::HashSet;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{symlink, FileTypeExt};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::string::String;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const NONE: Option<&'static [u8]> = None;
+
+/// Environment variables passed on to the command even with
+/// [`RunChroot::clear_env`].
+pub const PRESERVED_ENV: &[&str] = &["PATH", "TERM", "HOME"];
+
+/// Where the per-user profiles and channels are looked up by default.
+pub const PROFILE_BASE: &str = "/nix/var/nix/profiles/per-user";
+
+/// Used for `PATH` if the calling user has no profile of its own.
+pub const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+
+fn bind_mount(source: &Path, dest: &Path) -> nix::Result<()> {
+    mount(
+        Some(source),
+        dest,
+        Some("none"),
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        NONE,
+    )
+}
+
+/// An error with `message`, which names what failed, and the kind of
+/// `err`.
+fn error(message: String, err: impl Into<io::Error>) -> io::Error {
+    io::Error::new(err.into().kind(), message)
+}
+
+/// Bind mounts what the chroot can do without, like a single mirrored
+/// entry of the host, and remounts it with `flags` if there are any.
+///
+/// Only fails if the remount does, after unmounting `dest` again: the mount
+/// was asked to be restricted, keeping it as is would be worse than failing.
+fn bind_mount_or_warn(source: &Path, dest: &Path, flags: MsFlags) -> io::Result<()> {
+    if let Err(e) = bind_mount(source, dest) {
+        warn!(
+            "failed to bind mount {} to {}: {}",
+            source.display(),
+            dest.display(),
+            e
+        );
+        return Ok(());
+    }
+    if flags.is_empty() {
+        return Ok(());
+    }
+    let res = remount(source, dest, flags);
+    if res.is_err() {
+        let _ = umount2(dest, MntFlags::MNT_DETACH);
+    }
+    res
+}
+
+/// Sets the `lo` interface of the current network namespace up.
+fn bring_up_loopback() -> nix::Result<()> {
+    let sock = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
+    }
+
+    let res = unsafe {
+        if libc::ioctl(sock, libc::SIOCGIFFLAGS as _, &mut ifr) == -1 {
+            -1
+        } else {
+            ifr.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            libc::ioctl(sock, libc::SIOCSIFFLAGS as _, &ifr)
+        }
+    };
+    let res = Errno::result(res).map(drop);
+    let _ = unistd::close(sock);
+    res
+}
+
+/// The mount(8) names of the per-mount `flags` that [`remount`] sets.
+fn mount_options(flags: MsFlags) -> String {
+    [
+        (MsFlags::MS_RDONLY, "ro"),
+        (MsFlags::MS_NOSUID, "nosuid"),
+        (MsFlags::MS_NODEV, "nodev"),
+        (MsFlags::MS_NOEXEC, "noexec"),
+        (MsFlags::MS_NOATIME, "noatime"),
+        (MsFlags::MS_NODIRATIME, "nodiratime"),
+        (MsFlags::MS_RELATIME, "relatime"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Adds `flags` like `MS_RDONLY` or `MS_NOSUID` to the bind mount of
+/// `source` at `dest`.
+///
+/// Bind mounts ignore these flags on creation, so this needs a remount. The
+/// remount has to repeat the flags of the underlying mount since the kernel
+/// locks them when the mount is copied into our namespace. The error names
+/// the mount and all flags tried, as EINVAL or EPERM alone do not tell
+/// which of them the kernel refused.
+fn remount(source: &Path, dest: &Path, flags: MsFlags) -> io::Result<()> {
+    let failed = |flags: MsFlags, err: Errno| {
+        io::Error::new(
+            io::Error::from(err).kind(),
+            format!(
+                "failed to remount {} at {} with {}: {}",
+                source.display(),
+                dest.display(),
+                mount_options(flags),
+                err
+            ),
+        )
+    };
+    let fs_flags = statvfs(dest).map_err(|err| failed(flags, err))?.flags();
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | flags;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if fs_flags.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(None::<&str>, dest, None::<&str>, flags, NONE).map_err(|err| failed(flags, err))
+}
+
+/// Changes the propagation of the mount at `dest` and all mounts below it
+/// to `flag`, one of `MS_PRIVATE`, `MS_SLAVE` and `MS_SHARED`.
+fn set_propagation(dest: &str, flag: MsFlags) -> nix::Result<()> {
+    mount(
+        None::<&str>,
+        dest,
+        None::<&str>,
+        MsFlags::MS_REC | flag,
+        NONE,
+    )
+}
+
+/// Mounts a devpts instance of our own at `/dev/pts` and makes `/dev/ptmx`
+/// refer to its multiplexer, so that ptys allocated inside work.
+fn mount_devpts() -> nix::Result<()> {
+    mount(
+        Some("devpts"),
+        "/dev/pts",
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    )?;
+    // /dev is a bind mount of the host's, so /dev/ptmx cannot be replaced
+    // with a symlink without touching the host
+    mount(
+        Some("/dev/pts/ptmx"),
+        "/dev/ptmx",
+        None::<&str>,
+        MsFlags::MS_BIND,
+        NONE,
+    )
+}
+
+/// Mounts a new proc filesystem on top of `/proc` inside the chroot.
+///
+/// Without a PID namespace owned by our user namespace the kernel refuses
+/// this, in which case the `/proc` bind mounted from the host is kept.
+fn mount_proc() {
+    if let Err(e) = mount(
+        Some("proc"),
+        "/proc",
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        NONE,
+    ) {
+        warn!(
+            "failed to mount /proc: {}, keeping the /proc of the host",
+            e
+        );
+    }
+}
+
+/// Waits for the pid 1 of a new PID namespace and exits with its status.
+fn wait_for_init(child_pid: unistd::Pid) -> ! {
+    if let Err(e) = signals::forward_to(child_pid) {
+        warn!("failed to install signal handlers: {}", e);
+    }
+
+    loop {
+        match waitpid(child_pid, None) {
+            Ok(WaitStatus::Exited(_, status)) => process::exit(status),
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                signals::reraise(signal);
+                process::exit(128 + signal as i32);
+            }
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => {
+                eprintln!("waitpid failed: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Explains why unsharing the user namespace failed with `errno`, for the
+/// common cases where unprivileged user namespaces are not available.
+pub fn userns_hint(errno: Errno) -> Option<&'static str> {
+    match errno {
+        Errno::EPERM => Some(
+            "unprivileged user namespaces seem to be disabled. Check that \
+             `sysctl kernel.unprivileged_userns_clone` is 1 (Debian, Arch) and, on \
+             Ubuntu, that `sysctl kernel.apparmor_restrict_unprivileged_userns` is 0. \
+             Containers may also forbid unshare(2) with a seccomp filter.",
+        ),
+        Errno::ENOSPC => Some(
+            "the limit of user namespaces is reached or set to 0. Check \
+             `sysctl user.max_user_namespaces` and raise it, for example with \
+             `sysctl -w user.max_user_namespaces=15000`.",
+        ),
+        Errno::EUSERS => Some(
+            "user namespaces are nested too deeply, try again from outside of \
+             the current namespace.",
+        ),
+        Errno::EINVAL => Some(
+            "the kernel does not seem to support user namespaces, it has to be \
+             built with CONFIG_USER_NS=y.",
+        ),
+        _ => None,
+    }
+}
+
+fn deny_setgroups() {
+    if let Ok(mut file) = fs::File::create("/proc/self/setgroups") {
+        let _ = file.write_all(b"deny");
+    }
+}
+
+/// Writes the id mapping `map` to `/proc/self/<file>`.
+fn write_id_map(file: &str, map: &str) -> io::Result<()> {
+    let path = Path::new("/proc/self").join(file);
+    // the kernel only accepts the whole mapping in a single write
+    fs::write(&path, map)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot write {}: {}", path.display(), e)))
+}
+
+/// Whether the id mapping in `/proc/self/<file>` has been written already,
+/// e.g. by newuidmap.
+fn has_id_map(file: &str) -> bool {
+    fs::read_to_string(Path::new("/proc/self").join(file))
+        .map(|map| !map.trim().is_empty())
+        .unwrap_or(false)
+}
+
+/// A host path that is bind mounted to a given path inside the chroot, on
+/// top of whatever is mirrored from `/` there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplicitMount {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    pub readonly: bool,
+    /// Forbid executing the files of the mount, which would break
+    /// programs if applied to the whole mirrored tree.
+    pub noexec: bool,
+}
+
+/// A tmpfs mounted inside the chroot, shadowing whatever is mirrored from
+/// `/` there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TmpfsMount {
+    pub dest: PathBuf,
+    /// Passed as the `size=` mount option, e.g. `64m` or `10%`.
+    pub size: Option<String>,
+}
+
+/// Where the overlay of [`RunChroot::extra_store`] keeps its temporary
+/// files, relative to the nix directory. It has to be on the same
+/// filesystem as the store.
+pub const EXTRA_STORES_WORKDIR: &str = "var/nix/extra-stores.work";
+
+/// What the chroot gets at `/sys`, see [`RunChroot::sys`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysMount {
+    /// Mirror the `/sys` of the host like any other directory.
+    Host,
+    /// Mount a fresh read-only sysfs if there is a network namespace of
+    /// our own, or else make the mirrored `/sys` read-only.
+    ReadOnly,
+    /// Leave `/sys` out.
+    Hidden,
+}
+
+/// How mounts and unmounts propagate between the host and the chroot, see
+/// [`RunChroot::propagation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// Neither way.
+    Private,
+    /// From the host into the chroot.
+    Slave,
+    /// From the host into the chroot, and from the chroot to the mount
+    /// namespaces created inside of it.
+    Shared,
+}
+
+impl Propagation {
+    /// The propagation to start from. pivot_root(2) refuses shared mounts,
+    /// and shared mounts in the root could propagate into its own mount
+    /// point while mirroring, so [`Propagation::Shared`] is only applied
+    /// once the old root is gone.
+    fn initial_flag(self) -> MsFlags {
+        match self {
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Slave | Propagation::Shared => MsFlags::MS_SLAVE,
+        }
+    }
+}
+
+/// Whether to print the mounts of the finished chroot, see
+/// [`RunChroot::list_mounts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListMounts {
+    Off,
+    /// Print them to stderr and go on with the command.
+    ThenRun,
+    /// Print them to stdout and exit instead of running the command.
+    ThenExit,
+}
+
+/// A step taken while assembling the chroot, as listed by
+/// [`RunChroot::plan`]. Destinations are paths inside the chroot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Bind mount a directory over an empty directory.
+    BindDirectory { source: PathBuf, dest: PathBuf },
+    /// Bind mount a file or special file over an empty placeholder file.
+    BindFile { source: PathBuf, dest: PathBuf },
+    /// Recreate a symlink with the same target.
+    MirrorSymlink { link: PathBuf, target: PathBuf },
+    /// Create a symlink requested by the user.
+    Symlink { link: PathBuf, target: PathBuf },
+    /// Create an empty directory requested by the user.
+    Mkdir { path: PathBuf },
+    /// Create an empty file requested by the user.
+    Touch { path: PathBuf },
+    /// Write a file generated by us instead of mirroring the host's.
+    Generate { path: PathBuf },
+    /// Leave out a path that would otherwise be mirrored from `/`.
+    Exclude { path: PathBuf },
+    /// Bind mount a path requested by the user.
+    Explicit {
+        source: PathBuf,
+        dest: PathBuf,
+        readonly: bool,
+        noexec: bool,
+    },
+    /// Mount an empty tmpfs.
+    Tmpfs { dest: PathBuf, size: Option<String> },
+    /// Mount a fresh read-only sysfs.
+    Sysfs { dest: PathBuf },
+    /// Make a mirrored directory read-only.
+    RemountReadOnly { dest: PathBuf },
+    /// Mount an overlay with `lower` below what is at `dest` already.
+    Overlay { dest: PathBuf, lower: Vec<PathBuf> },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::BindDirectory { source, dest } => {
+                write!(
+                    f,
+                    "BIND DIRECTORY {} -> {}",
+                    source.display(),
+                    dest.display()
+                )
+            }
+            Action::BindFile { source, dest } => {
+                write!(f, "BIND FILE {} -> {}", source.display(), dest.display())
+            }
+            Action::MirrorSymlink { link, target } => {
+                write!(
+                    f,
+                    "MIRROR SYMLINK {} -> {}",
+                    link.display(),
+                    target.display()
+                )
+            }
+            Action::Symlink { link, target } => {
+                write!(f, "SYMLINK {} -> {}", link.display(), target.display())
+            }
+            Action::Mkdir { path } => write!(f, "MKDIR {}", path.display()),
+            Action::Touch { path } => write!(f, "TOUCH {}", path.display()),
+            Action::Generate { path } => write!(f, "GENERATE {}", path.display()),
+            Action::Exclude { path } => write!(f, "EXCLUDE {}", path.display()),
+            Action::Explicit {
+                source,
+                dest,
+                readonly,
+                noexec,
+            } => {
+                write!(f, "EXPLICIT {} -> {}", source.display(), dest.display())?;
+                match (readonly, noexec) {
+                    (true, true) => write!(f, " (read-only, noexec)"),
+                    (true, false) => write!(f, " (read-only)"),
+                    (false, true) => write!(f, " (noexec)"),
+                    (false, false) => Ok(()),
+                }
+            }
+            Action::Tmpfs { dest, size } => {
+                write!(f, "TMPFS {}", dest.display())?;
+                if let Some(size) = size {
+                    write!(f, " (size={})", size)?;
+                }
+                Ok(())
+            }
+            Action::Sysfs { dest } => write!(f, "SYSFS {} (read-only)", dest.display()),
+            Action::RemountReadOnly { dest } => {
+                write!(f, "REMOUNT {} (read-only)", dest.display())
+            }
+            Action::Overlay { dest, lower } => {
+                write!(f, "OVERLAY {} (below:", dest.display())?;
+                for dir in lower {
+                    write!(f, " {}", dir.display())?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Undoes the escaping of spaces, tabs, newlines and backslashes as `\ooo`
+/// in the fields of `/proc/self/mountinfo`.
+fn unescape_mountinfo(field: &str) -> OsString {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some((&c, tail)) = rest.split_first() {
+        let code = tail
+            .get(..3)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(code) if c == b'\\' => {
+                bytes.push(code);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(c);
+                rest = tail;
+            }
+        }
+    }
+    OsString::from_vec(bytes)
+}
+
+/// One line of `/proc/self/mountinfo`, see proc(5).
+struct MountInfo {
+    mount_point: PathBuf,
+    /// The directory of the source filesystem that is mounted, `/` unless
+    /// this is a bind mount of a subdirectory.
+    root: PathBuf,
+    source: OsString,
+    fs_type: String,
+    options: String,
+    /// The optional fields like `shared:1` or `master:2`.
+    propagation: Vec<String>,
+}
+
+impl MountInfo {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(' ');
+        let root = fields.nth(3)?;
+        let mount_point = fields.next()?;
+        let options = fields.next()?;
+        let propagation = fields
+            .by_ref()
+            .take_while(|&field| field != "-")
+            .map(String::from)
+            .collect();
+        let fs_type = fields.next()?;
+        let source = fields.next()?;
+        Some(Self {
+            mount_point: PathBuf::from(unescape_mountinfo(mount_point)),
+            root: PathBuf::from(unescape_mountinfo(root)),
+            source: unescape_mountinfo(source),
+            fs_type: String::from(fs_type),
+            options: String::from(options),
+            propagation,
+        })
+    }
+}
+
+/// Reads the mounts of the current mount namespace.
+fn mounts() -> Vec<MountInfo> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    mountinfo.lines().filter_map(MountInfo::parse).collect()
+}
+
+/// Reads the mount points of the current mount namespace.
+fn mount_points() -> Vec<PathBuf> {
+    mounts()
+        .into_iter()
+        .map(|mount| mount.mount_point)
+        .collect()
+}
+
+/// Prints the mounts of the current mount namespace as a table of mount
+/// point, filesystem type, source, mount options and propagation.
+fn print_mounts(out: &mut dyn Write) -> io::Result<()> {
+    let mounts = mounts();
+    if mounts.is_empty() {
+        warn!("failed to read /proc/self/mountinfo, is /proc mounted inside the chroot?");
+    }
+    let rows = mounts
+        .iter()
+        .map(|mount| {
+            let mut source = mount.source.to_string_lossy().into_owned();
+            if mount.root != Path::new("/") {
+                source = format!("{}[{}]", source, mount.root.display());
+            }
+            let propagation = if mount.propagation.is_empty() {
+                String::from("private")
+            } else {
+                mount.propagation.join(",")
+            };
+            [
+                mount.mount_point.display().to_string(),
+                mount.fs_type.clone(),
+                source,
+                mount.options.clone(),
+                propagation,
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+    for row in &rows {
+        for (width, column) in widths.iter().zip(row) {
+            write!(out, "{:<width$}  ", column, width = width)?;
+        }
+        writeln!(out, "{}", row[4])?;
+    }
+    Ok(())
+}
+
+/// The tmpfs holding the upper and work directories of the overlays
+/// mounted with [`RunChroot::overlay`].
+struct OverlayLayers {
+    dir: PathBuf,
+    /// Directories with other mounts below them, which an overlay would
+    /// hide.
+    mount_points: Vec<PathBuf>,
+    next: AtomicUsize,
+    warned: AtomicBool,
+}
+
+impl OverlayLayers {
+    fn can_overlay(&self, source: &Path) -> bool {
+        // the options of the overlay mount are separated by , and :
+        let special = source
+            .as_os_str()
+            .as_bytes()
+            .iter()
+            .any(|c| b",:\\".contains(c));
+        // the kernel refuses them as lower layer
+        let pseudo = statfs(source)
+            .is_ok_and(|fs| [PROC_SUPER_MAGIC, SYSFS_MAGIC].contains(&fs.filesystem_type()));
+        !special
+            && !pseudo
+            && !self
+                .mount_points
+                .iter()
+                .any(|path| path != source && path.starts_with(source))
+    }
+
+    /// Mounts an overlay of `source` to `dest`. Returns false if `dest`
+    /// has to be bind mounted instead.
+    fn mount(&self, source: &Path, dest: &Path, flags: MsFlags) -> bool {
+        let layer = self
+            .dir
+            .join(self.next.fetch_add(1, Ordering::Relaxed).to_string());
+        let (upper, work) = (layer.join("upper"), layer.join("work"));
+        for dir in [&upper, &work] {
+            fs::create_dir_all(dir)
+                .unwrap_or_else(|err| panic!("failed to create {}: {}", &dir.display(), err));
+        }
+        // userxattr is required for overlays created in a user namespace
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={},userxattr",
+            source.display(),
+            upper.display(),
+            work.display()
+        );
+        match mount(
+            Some("overlay"),
+            dest,
+            Some("overlay"),
+            flags,
+            Some(options.as_str()),
+        ) {
+            Ok(()) => true,
+            // some other filesystem the kernel refuses as lower layer
+            Err(Errno::EINVAL) => false,
+            Err(e) => {
+                if !self.warned.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "failed to mount overlay of {}: {}, bind mounting the host's directories \
+                         writable instead (unprivileged overlay mounts need Linux 5.11 or newer)",
+                        source.display(),
+                        e
+                    );
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Actions recorded during a dry run, together with the paths they would
+/// have created so that the walk takes the same decisions as a real run.
+struct Plan {
+    rootdir: PathBuf,
+    actions: Vec<Action>,
+    dirs: HashSet<PathBuf>,
+    files: HashSet<PathBuf>,
+}
+
+impl Plan {
+    fn new(rootdir: &Path) -> Self {
+        Self {
+            rootdir: rootdir.to_path_buf(),
+            actions: Vec::new(),
+            dirs: HashSet::new(),
+            files: HashSet::new(),
+        }
+    }
+
+    fn add_parents(&mut self, path: &Path) {
+        for parent in path.ancestors().skip(1) {
+            if parent == self.rootdir || !self.dirs.insert(parent.to_path_buf()) {
+                break;
+            }
+        }
+    }
+
+    fn inside(&self, path: &Path) -> PathBuf {
+        Path::new("/").join(path.strip_prefix(&self.rootdir).unwrap_or(path))
+    }
+
+    fn record(&mut self, action: Action) {
+        let action = match action {
+            Action::BindDirectory { source, dest } => {
+                self.add_parents(&dest);
+                self.dirs.insert(dest.clone());
+                Action::BindDirectory {
+                    source,
+                    dest: self.inside(&dest),
+                }
+            }
+            Action::BindFile { source, dest } => {
+                self.add_parents(&dest);
+                self.files.insert(dest.clone());
+                Action::BindFile {
+                    source,
+                    dest: self.inside(&dest),
+                }
+            }
+            Action::MirrorSymlink { link, target } => {
+                self.add_parents(&link);
+                self.files.insert(link.clone());
+                Action::MirrorSymlink {
+                    link: self.inside(&link),
+                    target,
+                }
+            }
+            Action::Symlink { link, target } => {
+                self.add_parents(&link);
+                self.files.insert(link.clone());
+                Action::Symlink {
+                    link: self.inside(&link),
+                    target,
+                }
+            }
+            Action::Mkdir { path } => {
+                self.add_parents(&path);
+                self.dirs.insert(path.clone());
+                Action::Mkdir {
+                    path: self.inside(&path),
+                }
+            }
+            Action::Touch { path } => {
+                self.add_parents(&path);
+                self.files.insert(path.clone());
+                Action::Touch {
+                    path: self.inside(&path),
+                }
+            }
+            Action::Generate { path } => {
+                self.add_parents(&path);
+                self.files.insert(path.clone());
+                Action::Generate {
+                    path: self.inside(&path),
+                }
+            }
+            Action::Exclude { path } => {
+                self.add_parents(&path);
+                Action::Exclude {
+                    path: self.inside(&path),
+                }
+            }
+            Action::Explicit {
+                source,
+                dest,
+                readonly,
+                noexec,
+            } => {
+                self.add_parents(&dest);
+                if source.is_dir() {
+                    self.dirs.insert(dest.clone());
+                } else {
+                    self.files.insert(dest.clone());
+                }
+                Action::Explicit {
+                    source,
+                    dest: self.inside(&dest),
+                    readonly,
+                    noexec,
+                }
+            }
+            Action::Tmpfs { dest, size } => {
+                self.add_parents(&dest);
+                self.dirs.insert(dest.clone());
+                Action::Tmpfs {
+                    dest: self.inside(&dest),
+                    size,
+                }
+            }
+            Action::Sysfs { dest } => Action::Sysfs {
+                dest: self.inside(&dest),
+            },
+            Action::RemountReadOnly { dest } => Action::RemountReadOnly {
+                dest: self.inside(&dest),
+            },
+            Action::Overlay { dest, lower } => {
+                self.add_parents(&dest);
+                self.dirs.insert(dest.clone());
+                Action::Overlay {
+                    dest: self.inside(&dest),
+                    lower,
+                }
+            }
+        };
+        self.actions.push(action);
+    }
+}
+
+/// Assembles a chroot below `rootdir` that mirrors the host's `/` and mounts
+/// a nix store at `/nix`.
+#[derive(Clone)]
+pub struct RunChroot {
+    rootdir: PathBuf,
+    uid: Uid,
+    gid: Gid,
+    unshare_pid: bool,
+    mount_proc: bool,
+    unshare_net: bool,
+    unshare_ipc: bool,
+    unshare_cgroup: bool,
+    sys: SysMount,
+    propagation: Propagation,
+    explicit_mounts: Vec<ExplicitMount>,
+    tmpfs_mounts: Vec<TmpfsMount>,
+    symlinks: Vec<(PathBuf, PathBuf)>,
+    mkdirs: Vec<PathBuf>,
+    touches: Vec<PathBuf>,
+    default_mounts: bool,
+    excludes: HashSet<PathBuf>,
+    include_roots: Option<HashSet<OsString>>,
+    env: Vec<(String, String)>,
+    clear_env: bool,
+    profile_path: bool,
+    generate_passwd: bool,
+    opengl: bool,
+    opengl_source: Option<PathBuf>,
+    extra_stores: Vec<PathBuf>,
+    use_chroot: bool,
+    subids: bool,
+    map_root: bool,
+    map_uid: Option<Uid>,
+    map_gid: Option<Gid>,
+    hostname: Option<String>,
+    devpts: bool,
+    pre_exec: Vec<String>,
+    profile_base: PathBuf,
+    workdir: Option<PathBuf>,
+    home: Option<PathBuf>,
+    resolv_conf: bool,
+    runtime_dir: bool,
+    fuse: bool,
+    devices: Vec<PathBuf>,
+    strict_mounts: bool,
+    bind_flags: MsFlags,
+    list_mounts: ListMounts,
+    umask: Option<Mode>,
+    keep_groups: bool,
+    setgroups: Option<bool>,
+    write_setgroups: bool,
+    persist_ns: Option<PathBuf>,
+    overlay: bool,
+    mirror_threads: Option<usize>,
+    overlay_layers: Option<Arc<OverlayLayers>>,
+    plan: Option<Arc<Mutex<Plan>>>,
+    // resolved sources and destinations of the explicit mounts made so far
+    mounted: Arc<Mutex<HashSet<(PathBuf, PathBuf)>>>,
+}
+
+impl RunChroot {
+    /// Creates a new chroot builder for `rootdir`.
+    ///
+    /// The uid and gid of the calling process are recorded here and later
+    /// mapped into the user namespace, so this has to be called before
+    /// unsharing.
+    pub fn new(rootdir: &Path) -> Self {
+        Self {
+            rootdir: rootdir.to_path_buf(),
+            uid: unistd::getuid(),
+            gid: unistd::getgid(),
+            unshare_pid: false,
+            mount_proc: false,
+            unshare_net: false,
+            unshare_ipc: false,
+            unshare_cgroup: false,
+            sys: SysMount::Host,
+            propagation: Propagation::Private,
+            explicit_mounts: Vec::new(),
+            tmpfs_mounts: Vec::new(),
+            symlinks: Vec::new(),
+            mkdirs: Vec::new(),
+            touches: Vec::new(),
+            default_mounts: true,
+            excludes: HashSet::new(),
+            include_roots: None,
+            env: Vec::new(),
+            clear_env: false,
+            profile_path: true,
+            generate_passwd: false,
+            opengl: true,
+            opengl_source: None,
+            extra_stores: Vec::new(),
+            use_chroot: false,
+            subids: false,
+            map_root: false,
+            map_uid: None,
+            map_gid: None,
+            hostname: None,
+            devpts: false,
+            pre_exec: Vec::new(),
+            profile_base: PathBuf::from(PROFILE_BASE),
+            workdir: None,
+            home: None,
+            resolv_conf: false,
+            runtime_dir: false,
+            fuse: false,
+            devices: Vec::new(),
+            strict_mounts: false,
+            bind_flags: MsFlags::empty(),
+            list_mounts: ListMounts::Off,
+            umask: None,
+            keep_groups: false,
+            setgroups: None,
+            write_setgroups: true,
+            persist_ns: None,
+            overlay: false,
+            mirror_threads: None,
+            overlay_layers: None,
+            plan: None,
+            mounted: Arc::default(),
+        }
+    }
+
+    /// Returns a builder with the same settings that operates on `rootdir`.
+    pub fn with_rootdir(&self, rootdir: &Path) -> Self {
+        Self {
+            rootdir: rootdir.to_path_buf(),
+            ..self.clone()
+        }
+    }
+
+    /// Runs the command as pid 1 of a new PID namespace.
+    pub fn unshare_pid(mut self, enable: bool) -> Self {
+        self.unshare_pid = enable;
+        self
+    }
+
+    /// Runs the command in a new network namespace that only has a
+    /// loopback interface.
+    pub fn unshare_net(mut self, enable: bool) -> Self {
+        self.unshare_net = enable;
+        self
+    }
+
+    /// Runs the command in a new IPC namespace, so that it can neither see
+    /// nor modify the System V IPC objects and POSIX message queues of the
+    /// host.
+    pub fn unshare_ipc(mut self, enable: bool) -> Self {
+        self.unshare_ipc = enable;
+        self
+    }
+
+    /// Runs the command in a new cgroup namespace, so that the cgroup it
+    /// starts in appears as the root in `/proc/self/cgroup`. Kernels
+    /// without cgroup namespaces only get a warning.
+    pub fn unshare_cgroup(mut self, enable: bool) -> Self {
+        self.unshare_cgroup = enable;
+        self
+    }
+
+    /// Chooses what the chroot gets at `/sys`. By default the host's is
+    /// mirrored, which exposes details about its hardware and drivers.
+    ///
+    /// The kernel only allows mounting a fresh sysfs, which shows the
+    /// network devices of the current network namespace, together with
+    /// [`unshare_net`](Self::unshare_net). Otherwise
+    /// [`SysMount::ReadOnly`] remounts the mirrored `/sys` read-only, which
+    /// leaves the filesystems mounted below it, like `/sys/fs/cgroup`,
+    /// writable.
+    pub fn sys(mut self, mode: SysMount) -> Self {
+        self.sys = mode;
+        self
+    }
+
+    /// Sets whether mounts and unmounts on the host show up inside the
+    /// chroot, which matters for a long-running chroot that should see
+    /// filesystems mounted later, like removable media. Mounts inside never
+    /// propagate to the host. Defaults to [`Propagation::Private`].
+    pub fn propagation(mut self, mode: Propagation) -> Self {
+        self.propagation = mode;
+        self
+    }
+
+    /// Adds a bind mount of a host path into the chroot. `dest` has to be
+    /// absolute; a later mount to the same `dest` replaces an earlier one.
+    pub fn explicit_mount(mut self, mount: ExplicitMount) -> Self {
+        self.explicit_mounts.retain(|m| m.dest != mount.dest);
+        self.explicit_mounts.push(mount);
+        self
+    }
+
+    /// Mounts an empty tmpfs inside the chroot once everything else is
+    /// mounted. `dest` has to be absolute; a later tmpfs at the same `dest`
+    /// replaces an earlier one.
+    pub fn tmpfs(mut self, mount: TmpfsMount) -> Self {
+        self.tmpfs_mounts.retain(|m| m.dest != mount.dest);
+        self.tmpfs_mounts.push(mount);
+        self
+    }
+
+    /// Creates a symlink at the absolute path `link` inside the chroot that
+    /// points to `target`, instead of mirroring what the host has there.
+    /// A later symlink at the same `link` replaces an earlier one.
+    pub fn symlink(mut self, link: &Path, target: &Path) -> Self {
+        self.symlinks.retain(|(l, _)| l != link);
+        self.symlinks
+            .push((link.to_path_buf(), target.to_path_buf()));
+        self
+    }
+
+    /// Makes sure that the absolute path `path` is a directory inside the
+    /// chroot. If the host has a directory there, its content is mirrored
+    /// into it.
+    pub fn mkdir(mut self, path: &Path) -> Self {
+        self.mkdirs.push(path.to_path_buf());
+        self
+    }
+
+    /// Creates an empty file at the absolute path `path` inside the chroot,
+    /// instead of mirroring what the host has there.
+    pub fn touch(mut self, path: &Path) -> Self {
+        self.touches.push(path.to_path_buf());
+        self
+    }
+
+    /// Runs the command in a new UTS namespace with the hostname `name`.
+    pub fn hostname(mut self, name: &str) -> Self {
+        self.hostname = Some(name.to_owned());
+        self
+    }
+
+    /// Mirrors the entries of the host's `/` into the chroot, which is the
+    /// default. Without it the chroot only contains `/nix`, the opengl
+    /// driver directory and what is added explicitly. Most dynamically
+    /// linked programs then need at least `/lib` to be mounted.
+    pub fn default_mounts(mut self, enable: bool) -> Self {
+        self.default_mounts = enable;
+        self
+    }
+
+    /// Mirrors directories of the host with an overlay instead of a bind
+    /// mount, so that changes inside end up in a tmpfs and are discarded
+    /// when the command exits. Unprivileged users can only mount overlays
+    /// since Linux 5.11.
+    ///
+    /// Directories with other filesystems mounted below them, like `/dev`,
+    /// and directories on filesystems that cannot be overlaid, like
+    /// `/proc`, are still bind mounted, and so are files that are mirrored
+    /// one by one next to explicit mounts or excluded paths.
+    pub fn overlay(mut self, enable: bool) -> Self {
+        self.overlay = enable;
+        self
+    }
+
+    /// Mirrors the top-level entries of `/` with up to `threads` threads,
+    /// counting the calling one, instead of one per available CPU. `1`
+    /// mirrors serially, for example to compare against in benchmarks.
+    pub fn mirror_threads(mut self, threads: usize) -> Self {
+        self.mirror_threads = Some(threads);
+        self
+    }
+
+    /// Leaves the absolute host path `path` out when mirroring `/`. A
+    /// directory is left out together with everything below it.
+    ///
+    /// Symlinks in the parent directories are resolved, so that excluding
+    /// `/lib/foo` also works if `/lib` points to `/usr/lib`.
+    pub fn exclude(mut self, path: &Path) -> Self {
+        let path = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => fs::canonicalize(parent)
+                .map(|parent| parent.join(name))
+                .unwrap_or_else(|_| path.to_path_buf()),
+            _ => path.to_path_buf(),
+        };
+        self.excludes.insert(path);
+        self
+    }
+
+    /// Only mirrors the top-level entry `name` of `/`, together with the
+    /// ones of other calls. Without any call, everything but `/nix` is
+    /// mirrored. Excludes below `name` still apply.
+    pub fn include_root(mut self, name: &OsStr) -> Self {
+        self.include_roots
+            .get_or_insert_with(HashSet::new)
+            .insert(name.to_owned());
+        self
+    }
+
+    /// Starts the command in `dir` inside the chroot instead of the current
+    /// working directory.
+    pub fn workdir(mut self, dir: &Path) -> Self {
+        self.workdir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Sets `HOME` of the command to `dir`. Unless the host's `dir` is
+    /// mirrored, an empty directory is created there inside the chroot.
+    pub fn home(mut self, dir: &Path) -> Self {
+        self.home = Some(dir.to_path_buf());
+        self
+    }
+
+    /// The flags of [`nosuid`](Self::nosuid) and [`nodev`](Self::nodev) for
+    /// a bind mount of the host path `source`.
+    ///
+    /// Only the bind mount itself gets them, mounts below it like
+    /// `/dev/pts` keep their own flags.
+    fn bind_flags(&self, source: &Path) -> MsFlags {
+        let mut flags = self.bind_flags;
+        if source.starts_with("/dev") {
+            flags.remove(MsFlags::MS_NODEV);
+        }
+        flags
+    }
+
+    /// Bind mounts the host's `device` to the same path, unless mirroring
+    /// `/dev` brings it along.
+    fn mount_device(&self, device: &Path, nixdir: &Path) -> io::Result<()> {
+        let mirrored = device.parent().is_some_and(|dir| self.is_mirrored(dir))
+            && !self.excludes.contains(device);
+        if mirrored {
+            return Ok(());
+        }
+        self.mount_explicit(
+            &ExplicitMount {
+                source: device.to_path_buf(),
+                dest: device.to_path_buf(),
+                readonly: false,
+                noexec: false,
+            },
+            nixdir,
+        )
+    }
+
+    /// Whether the host's `path` shows up inside when mirroring `/`.
+    fn is_mirrored(&self, path: &Path) -> bool {
+        let top_level = path.components().nth(1).map(|c| c.as_os_str());
+        self.default_mounts
+            && path.is_dir()
+            && top_level != Some(OsStr::new("nix"))
+            && match (&self.include_roots, top_level) {
+                (Some(names), Some(name)) => names.contains(name),
+                _ => true,
+            }
+            && !path
+                .ancestors()
+                .any(|parent| self.excludes.contains(parent))
+    }
+
+    /// Sets the umask of the command and the pre-exec hooks instead of
+    /// inheriting ours.
+    pub fn umask(mut self, mode: Mode) -> Self {
+        self.umask = Some(mode);
+        self
+    }
+
+    /// Sets an environment variable for the command, overriding defaults
+    /// such as `NIX_CONF_DIR`.
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Starts the command with an empty environment except for the
+    /// variables in [`PRESERVED_ENV`] and the ones set by us or with
+    /// [`env`](Self::env).
+    pub fn clear_env(mut self, enable: bool) -> Self {
+        self.clear_env = enable;
+        self
+    }
+
+    /// Prepends the `bin` directory of the user's nix profile to `PATH`,
+    /// like a login shell on NixOS does. Enabled by default.
+    pub fn profile_path(mut self, enable: bool) -> Self {
+        self.profile_path = enable;
+        self
+    }
+
+    /// Looks up the per-user profile and channels in `<dir>/<user>` instead
+    /// of [`PROFILE_BASE`]. `dir` is a path inside the chroot.
+    pub fn profile_base(mut self, dir: &Path) -> Self {
+        self.profile_base = dir.to_path_buf();
+        self
+    }
+
+    /// Resolves `/etc/resolv.conf` on the host and bind mounts the file it
+    /// points to read-only to `/etc/resolv.conf`, for hosts where it is a
+    /// symlink into a directory that does not work inside, such as the one
+    /// of systemd-resolved in `/run`. Explicit mounts to `/etc/resolv.conf`
+    /// take precedence.
+    pub fn resolv_conf(mut self, enable: bool) -> Self {
+        self.resolv_conf = enable;
+        self
+    }
+
+    /// Exits instead of warning when an explicit mount is skipped, or when
+    /// its source is inside `/nix` or the nix directory but resolves to a
+    /// path outside of it, for example through a symlink out of the store.
+    /// This includes the mounts of [`resolv_conf`](Self::resolv_conf) and
+    /// [`runtime_dir`](Self::runtime_dir).
+    pub fn strict_mounts(mut self, enable: bool) -> Self {
+        self.strict_mounts = enable;
+        self
+    }
+
+    /// Ignores setuid and setgid bits and file capabilities on everything
+    /// bind mounted from the host, so that no program inside can gain
+    /// privileges through them.
+    pub fn nosuid(mut self, enable: bool) -> Self {
+        self.bind_flags.set(MsFlags::MS_NOSUID, enable);
+        self
+    }
+
+    /// Forbids opening device nodes on everything bind mounted from the
+    /// host except `/dev`, whose devices the programs inside need.
+    pub fn nodev(mut self, enable: bool) -> Self {
+        self.bind_flags.set(MsFlags::MS_NODEV, enable);
+        self
+    }
+
+    /// Makes sure that `/dev/fuse` is there for FUSE filesystems like
+    /// squashfuse or fuse-overlayfs, even if the host's `/dev` is not
+    /// mirrored. Mounting them additionally needs
+    /// [`map_root`](Self::map_root), since fusermount(1) cannot gain
+    /// privileges inside the user namespace.
+    pub fn fuse(mut self, enable: bool) -> Self {
+        self.fuse = enable;
+        self
+    }
+
+    /// Bind mounts the host's device node `device`, a path below `/dev`,
+    /// to the same path, for access to specific hardware like
+    /// `/dev/dri/card0` without mirroring all of `/dev`. Can be called
+    /// multiple times.
+    pub fn device(mut self, device: &Path) -> Self {
+        self.devices.push(device.to_path_buf());
+        self
+    }
+
+    /// Prints the mount table as the kernel sees it inside the finished
+    /// chroot, right before the command would run. Unlike
+    /// [`plan`](Self::plan), this shows what actually got mounted, with the
+    /// mount options and propagation of each mount.
+    pub fn list_mounts(mut self, mode: ListMounts) -> Self {
+        self.list_mounts = mode;
+        self
+    }
+
+    /// Bind mounts the host's `$XDG_RUNTIME_DIR` to the same path, for
+    /// programs that talk to the session's dbus, audio or wayland sockets
+    /// in it, and passes the variable on even with
+    /// [`clear_env`](Self::clear_env). Explicit mounts to the same path
+    /// take precedence.
+    pub fn runtime_dir(mut self, enable: bool) -> Self {
+        self.runtime_dir = enable;
+        self
+    }
+
+    /// Writes an `/etc/passwd` and `/etc/group` that only contain the
+    /// calling user and its primary group instead of mirroring the host's.
+    pub fn generate_passwd(mut self, enable: bool) -> Self {
+        self.generate_passwd = enable;
+        self
+    }
+
+    /// Bind mounts `var/nix/opengl-driver/lib` of the nix directory to
+    /// `/run/opengl-driver/lib` if it exists. Enabled by default.
+    pub fn opengl(mut self, enable: bool) -> Self {
+        self.opengl = enable;
+        self
+    }
+
+    /// Mounts `dir` instead of `var/nix/opengl-driver/lib` of the nix
+    /// directory to `/run/opengl-driver/lib`.
+    pub fn opengl_source(mut self, dir: &Path) -> Self {
+        self.opengl_source = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Makes the store paths in `<dir>/store` available in `/nix/store` as
+    /// well. Later calls add stores with a lower precedence: a store path
+    /// that exists in several stores is taken from the nix directory first,
+    /// then from the extra stores in the order they were added.
+    ///
+    /// The stores are combined with an overlay mount, which unprivileged
+    /// users can only create since Linux 5.11. New store paths are written
+    /// to the store of the nix directory, which also keeps the temporary
+    /// files of the overlay in [`EXTRA_STORES_WORKDIR`]. Store paths from
+    /// extra stores are not registered in its nix database.
+    pub fn extra_store(mut self, dir: &Path) -> Self {
+        self.extra_stores.push(dir.to_path_buf());
+        self
+    }
+
+    /// Enters the root directory with chroot(2) instead of pivot_root(2).
+    ///
+    /// A chroot can be escaped by processes that keep a file descriptor to
+    /// a directory outside of it, so this is only meant for kernels or
+    /// filesystems where pivot_root fails.
+    pub fn use_chroot(mut self, enable: bool) -> Self {
+        self.use_chroot = enable;
+        self
+    }
+
+    /// Additionally maps the subordinate ids of the calling user from
+    /// `/etc/subuid` and `/etc/subgid` with newuidmap(1) and newgidmap(1),
+    /// so that other ids exist inside the namespace. Falls back to mapping
+    /// only the calling user if that fails.
+    pub fn subids(mut self, enable: bool) -> Self {
+        self.subids = enable;
+        self
+    }
+
+    /// Keeps the supplementary groups of the calling user inside the user
+    /// namespace by mapping them with newgidmap(1). Otherwise they show up
+    /// as the overflow gid, usually `nogroup`. This only works for groups
+    /// that `/etc/subgid` assigns to the user; if it fails, only the
+    /// primary group is mapped.
+    pub fn keep_groups(mut self, enable: bool) -> Self {
+        self.keep_groups = enable;
+        self
+    }
+
+    /// Overrides whether setgroups(2) is allowed inside the user namespace.
+    ///
+    /// By default it is denied when we write the gid mapping ourselves, as
+    /// the kernel requires that for unprivileged users, and left allowed
+    /// when newgidmap writes a mapping of several groups. It cannot be
+    /// allowed with a mapping written by us.
+    pub fn setgroups(mut self, allow: bool) -> Self {
+        self.setgroups = Some(allow);
+        self
+    }
+
+    /// Whether `/proc/self/setgroups` is written at all. Without it,
+    /// [`setgroups`](Self::setgroups) has no effect and the state is
+    /// inherited from the parent user namespace. The kernel only accepts a
+    /// gid map written by us if setgroups(2) is denied already, for example
+    /// inside an outer user namespace that denied it.
+    pub fn write_setgroups(mut self, enable: bool) -> Self {
+        self.write_setgroups = enable;
+        self
+    }
+
+    /// Bind mounts the files of the new namespaces from `/proc/<pid>/ns`
+    /// onto files named after them in `dir`, which keeps the namespaces
+    /// alive after the command exits so that nsenter(1) can join them.
+    ///
+    /// The bind mounts are made in the mount namespace of the caller, which
+    /// requires the privileges to mount there, and are never unmounted by
+    /// us. The PID namespace cannot be persisted, as it dies with its first
+    /// process.
+    pub fn persist_ns(mut self, dir: &Path) -> Self {
+        self.persist_ns = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Maps the calling user and its primary group to root inside the user
+    /// namespace instead of to their own ids. Files created as root inside
+    /// are still owned by the calling user outside.
+    pub fn map_root(mut self, enable: bool) -> Self {
+        self.map_root = enable;
+        self
+    }
+
+    /// Maps the calling user to `uid` inside the user namespace, for
+    /// example to match the owner of files in a bind mounted directory.
+    /// Takes precedence over [`map_root`](Self::map_root).
+    pub fn map_uid(mut self, uid: Uid) -> Self {
+        self.map_uid = Some(uid);
+        self
+    }
+
+    /// Maps the primary group of the calling user to `gid` inside the user
+    /// namespace. Takes precedence over [`map_root`](Self::map_root).
+    pub fn map_gid(mut self, gid: Gid) -> Self {
+        self.map_gid = Some(gid);
+        self
+    }
+
+    /// The uid and gid of the calling user inside the user namespace.
+    fn inside_ids(&self) -> (Uid, Gid) {
+        let (uid, gid) = if self.map_root {
+            (Uid::from_raw(0), Gid::from_raw(0))
+        } else {
+            (self.uid, self.gid)
+        };
+        (self.map_uid.unwrap_or(uid), self.map_gid.unwrap_or(gid))
+    }
+
+    /// Mounts a new devpts instance over the `/dev/pts` mirrored from the
+    /// host, for programs that allocate ptys like tmux or screen.
+    pub fn devpts(mut self, enable: bool) -> Self {
+        self.devpts = enable;
+        self
+    }
+
+    /// Runs the shell command `cmd` inside the chroot before the command
+    /// itself, with the same environment. Hooks run in the order they were
+    /// added; if one fails, we exit with its status.
+    pub fn pre_exec(mut self, cmd: &str) -> Self {
+        self.pre_exec.push(cmd.to_owned());
+        self
+    }
+
+    /// Mounts a fresh proc filesystem over the `/proc` mirrored from the
+    /// host, so that it reflects the processes of our own PID namespace.
+    pub fn mount_proc(mut self, enable: bool) -> Self {
+        self.mount_proc = enable;
+        self
+    }
+
+    /// Records `action` instead of performing it when doing a dry run.
+    /// Returns whether it was recorded.
+    fn dry_run(&self, action: impl FnOnce() -> Action) -> bool {
+        match &self.plan {
+            Some(plan) => {
+                plan.lock().unwrap().record(action());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        if let Some(plan) = &self.plan {
+            let plan = plan.lock().unwrap();
+            if plan.dirs.contains(path) || plan.files.contains(path) {
+                return true;
+            }
+        }
+        // a dangling symlink exists as well
+        path.symlink_metadata().is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        if let Some(plan) = &self.plan {
+            if plan.lock().unwrap().dirs.contains(path) {
+                return true;
+            }
+        }
+        // do not recurse through symlinks created by us
+        path.symlink_metadata()
+            .map(|metadata| metadata.is_dir())
+            .unwrap_or(false)
+    }
+
+    fn bind_mount_directory(&self, entry: &fs::DirEntry) -> io::Result<()> {
+        let mountpoint = self.rootdir.join(entry.file_name());
+
+        // if the destination doesn't exist we can proceed as normal
+        if !self.exists(&mountpoint) {
+            let source = entry.path();
+            let overlay = self
+                .overlay_layers
+                .as_ref()
+                .filter(|layers| layers.can_overlay(&source));
+            let recorded = self.dry_run(|| match overlay {
+                Some(_) => Action::Overlay {
+                    dest: mountpoint.clone(),
+                    lower: vec![source.clone()],
+                },
+                None => Action::BindDirectory {
+                    source: source.clone(),
+                    dest: mountpoint.clone(),
+                },
+            });
+            if recorded {
+                return Ok(());
+            }
+
+            if let Err(e) = fs::create_dir(&mountpoint) {
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    panic!("failed to create {}: {}", &mountpoint.display(), e);
+                }
+            }
+
+            let flags = self.bind_flags(&source);
+            if overlay.is_some_and(|layers| layers.mount(&source, &mountpoint, flags)) {
+                return Ok(());
+            }
+            bind_mount_or_warn(&source, &mountpoint, flags)
+        } else {
+            // otherwise, if the dest is also a dir, we can recurse into it
+            // and mount subdirectory siblings of existing paths
+            if self.is_dir(&mountpoint) {
+                let dir = fs::read_dir(entry.path()).unwrap_or_else(|err| {
+                    panic!("failed to list dir {}: {}", entry.path().display(), err)
+                });
+
+                let child = self.with_rootdir(&mountpoint);
+                for entry in dir {
+                    let entry = entry.expect("error while listing subdir");
+                    child.bind_mount_direntry(&entry)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn bind_mount_file(&self, entry: &fs::DirEntry) -> io::Result<()> {
+        let mountpoint = self.rootdir.join(entry.file_name());
+        if self.exists(&mountpoint) {
+            return Ok(());
+        }
+        let recorded = self.dry_run(|| Action::BindFile {
+            source: entry.path(),
+            dest: mountpoint.clone(),
+        });
+        if recorded {
+            return Ok(());
+        }
+
+        fs::File::create(&mountpoint)
+            .unwrap_or_else(|err| panic!("failed to create {}: {}", &mountpoint.display(), err));
+
+        bind_mount_or_warn(&entry.path(), &mountpoint, self.bind_flags(&entry.path()))
+    }
+
+    fn mirror_symlink(&self, entry: &fs::DirEntry) {
+        let link_path = self.rootdir.join(entry.file_name());
+        if self.exists(&link_path) {
+            return;
+        }
+        let path = entry.path();
+        let target = fs::read_link(&path)
+            .unwrap_or_else(|err| panic!("failed to resolve symlink {}: {}", &path.display(), err));
+        let recorded = self.dry_run(|| Action::MirrorSymlink {
+            link: link_path.clone(),
+            target: target.clone(),
+        });
+        if recorded {
+            return;
+        }
+
+        symlink(&target, &link_path).unwrap_or_else(|_| {
+            panic!(
+                "failed to create symlink {} -> {}",
+                &link_path.display(),
+                &target.display()
+            )
+        });
+    }
+
+    /// Reports a problem with an explicit mount, which is an error with
+    /// [`strict_mounts`](Self::strict_mounts).
+    fn mount_problem(&self, message: &str) -> io::Result<()> {
+        if self.strict_mounts {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}\naborting because of --strict-mounts", message),
+            ));
+        }
+        warn!("{}", message);
+        Ok(())
+    }
+
+    /// Bind mounts `mount` into the root directory. Fails if creating the
+    /// mountpoint or mounting does, as the command may rely on the mount.
+    fn mount_explicit(&self, mount: &ExplicitMount, nixdir: &Path) -> io::Result<()> {
+        // "/" or "/.." would cover the whole root
+        if mount.dest.file_name().is_none() {
+            self.mount_problem(&format!(
+                "cannot bind mount {} over {}, skipping",
+                mount.source.display(),
+                mount.dest.display()
+            ))?;
+            return Ok(());
+        }
+        let dest = self
+            .rootdir
+            .join(mount.dest.strip_prefix("/").unwrap_or(&mount.dest));
+        if !mount.source.exists() {
+            self.mount_problem(&format!(
+                "source {} of bind mount to {} does not exist, skipping",
+                mount.source.display(),
+                mount.dest.display()
+            ))?;
+            return Ok(());
+        }
+        let store = [Path::new("/nix"), nixdir]
+            .iter()
+            .copied()
+            .find(|dir| mount.source.starts_with(dir));
+        if let Some(dir) = store {
+            if let (Ok(source), Ok(dir)) = (fs::canonicalize(&mount.source), fs::canonicalize(dir))
+            {
+                if !source.starts_with(&dir) {
+                    self.mount_problem(&format!(
+                        "source {} of bind mount to {} resolves to {} outside of {}",
+                        mount.source.display(),
+                        mount.dest.display(),
+                        source.display(),
+                        dir.display()
+                    ))?;
+                }
+            }
+        }
+
+        // e.g. overlapping --device globs or --fuse with --device /dev/fuse,
+        // which would stack a second mount on the first. Hardlinks to the
+        // same file at other destinations still need mounts of their own.
+        let source = fs::canonicalize(&mount.source).unwrap_or_else(|_| mount.source.clone());
+        if !self.mounted.lock().unwrap().insert((source, dest.clone())) {
+            return Ok(());
+        }
+
+        let recorded = self.dry_run(|| Action::Explicit {
+            source: mount.source.clone(),
+            dest: dest.clone(),
+            readonly: mount.readonly,
+            noexec: mount.noexec,
+        });
+        if recorded {
+            return Ok(());
+        }
+
+        // the mountpoint is created before / is mirrored, so the mirroring
+        // fills in the siblings of its parents
+        let created = if mount.source.is_dir() {
+            fs::create_dir_all(&dest).map_err(|e| (e, dest.as_path()))
+        } else {
+            let parent = dest.parent().unwrap_or(&dest);
+            fs::create_dir_all(parent)
+                .map_err(|e| (e, parent))
+                .and_then(|_| {
+                    fs::File::create(&dest)
+                        .map(drop)
+                        .map_err(|e| (e, dest.as_path()))
+                })
+        };
+        created
+            .map_err(|(e, path)| error(format!("failed to create {}: {}", path.display(), e), e))?;
+
+        // the command may rely on it, so this is fatal
+        bind_mount(&mount.source, &dest).map_err(|err| {
+            error(
+                format!(
+                    "failed to bind mount {} to {}: {}",
+                    mount.source.display(),
+                    mount.dest.display(),
+                    err
+                ),
+                err,
+            )
+        })?;
+        let mut flags = self.bind_flags(&mount.source);
+        if mount.readonly {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        if mount.noexec {
+            flags |= MsFlags::MS_NOEXEC;
+        }
+        if !flags.is_empty() {
+            remount(&mount.source, &dest, flags)?;
+        }
+        Ok(())
+    }
+
+    fn mount_tmpfs(&self, tmpfs: &TmpfsMount) {
+        let dest = self
+            .rootdir
+            .join(tmpfs.dest.strip_prefix("/").unwrap_or(&tmpfs.dest));
+        let recorded = self.dry_run(|| Action::Tmpfs {
+            dest: dest.clone(),
+            size: tmpfs.size.clone(),
+        });
+        if recorded {
+            return;
+        }
+
+        // usually the directory is mirrored from the host already
+        fs::create_dir_all(&dest)
+            .unwrap_or_else(|err| panic!("failed to create {}: {}", &dest.display(), err));
+        let options = tmpfs.size.as_ref().map(|size| format!("size={}", size));
+        mount(
+            Some("tmpfs"),
+            &dest,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            options.as_deref(),
+        )
+        .unwrap_or_else(|err| panic!("failed to mount tmpfs to {}: {}", tmpfs.dest.display(), err));
+    }
+
+    fn mount_sys(&self) {
+        let dest = self.rootdir.join("sys");
+        // left out by include_root
+        if !self.is_dir(&dest) {
+            return;
+        }
+
+        if self.unshare_net {
+            if self.dry_run(|| Action::Sysfs { dest: dest.clone() }) {
+                return;
+            }
+            match mount(
+                Some("sysfs"),
+                &dest,
+                Some("sysfs"),
+                MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                NONE,
+            ) {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "failed to mount sysfs: {}, making the /sys of the host read-only",
+                    e
+                ),
+            }
+        } else if self.dry_run(|| Action::RemountReadOnly { dest: dest.clone() }) {
+            return;
+        }
+
+        if let Err(e) = remount(Path::new("/sys"), &dest, MsFlags::MS_RDONLY) {
+            warn!("{}", e);
+        }
+    }
+
+    fn mount_extra_stores(&self, nixdir: &Path) {
+        let dest = self.rootdir.join("nix/store");
+        let lower = self
+            .extra_stores
+            .iter()
+            .map(|dir| dir.join("store"))
+            .collect::<Vec<_>>();
+        let recorded = self.dry_run(|| Action::Overlay {
+            dest: dest.clone(),
+            lower: lower.clone(),
+        });
+        if recorded {
+            return;
+        }
+
+        // the store may not exist yet in a directory to install nix to
+        let upper = nixdir.join("store");
+        let work = nixdir.join(EXTRA_STORES_WORKDIR);
+        for dir in [&upper, &work, &dest] {
+            fs::create_dir_all(dir)
+                .unwrap_or_else(|err| panic!("failed to create {}: {}", &dir.display(), err));
+        }
+        let lowerdir = lower
+            .iter()
+            .map(|dir| dir.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(":");
+        // userxattr is required for overlays created in a user namespace
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={},userxattr",
+            lowerdir,
+            upper.display(),
+            work.display()
+        );
+        if let Err(e) = mount(
+            Some("overlay"),
+            &dest,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        ) {
+            warn!(
+                "failed to mount the extra stores to /nix/store: {}, they are not available \
+                 (unprivileged overlay mounts need Linux 5.11 or newer)",
+                e
+            );
+        }
+    }
+
+    fn create_symlink(&self, link: &Path, target: &Path) {
+        let path = self.rootdir.join(link.strip_prefix("/").unwrap_or(link));
+        let recorded = self.dry_run(|| Action::Symlink {
+            link: path.clone(),
+            target: target.to_path_buf(),
+        });
+        if recorded {
+            return;
+        }
+
+        // created before / is mirrored, so the mirroring fills in the
+        // siblings of the symlink and its parents
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .unwrap_or_else(|err| panic!("failed to create {}: {}", &parent.display(), err));
+        }
+        if let Err(e) = symlink(target, &path) {
+            // left over in a reused --rootdir
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                panic!(
+                    "failed to create symlink {} -> {}: {}",
+                    link.display(),
+                    target.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn create_path(&self, path: &Path, dir: bool) {
+        let dest = self.rootdir.join(path.strip_prefix("/").unwrap_or(path));
+        let recorded = self.dry_run(|| {
+            if dir {
+                Action::Mkdir { path: dest.clone() }
+            } else {
+                Action::Touch { path: dest.clone() }
+            }
+        });
+        if recorded {
+            return;
+        }
+
+        // created before / is mirrored, like symlinks
+        let parent = if dir {
+            Some(dest.as_path())
+        } else {
+            dest.parent()
+        };
+        if let Some(parent) = parent {
+            fs::create_dir_all(parent)
+                .unwrap_or_else(|err| panic!("failed to create {}: {}", &parent.display(), err));
+        }
+        if !dir {
+            if let Err(e) = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&dest)
+            {
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    panic!("failed to create {}: {}", &dest.display(), e);
+                }
+            }
+        }
+    }
+
+    fn write_passwd(&self) {
+        let user = match unistd::User::from_uid(self.uid) {
+            Ok(Some(user)) => user,
+            _ => {
+                warn!(
+                    "cannot look up user {}, not generating /etc/passwd",
+                    self.uid
+                );
+                return;
+            }
+        };
+        let group = match unistd::Group::from_gid(self.gid) {
+            Ok(Some(group)) => group.name,
+            _ => user.name.clone(),
+        };
+        let (uid, gid) = self.inside_ids();
+        let passwd = format!(
+            "{}:x:{}:{}:{}:{}:{}\n",
+            user.name,
+            uid,
+            gid,
+            user.gecos.to_string_lossy(),
+            user.dir.display(),
+            user.shell.display()
+        );
+        let group = format!("{}:x:{}:\n", group, gid);
+
+        // written before / is mirrored, so the rest of /etc still comes
+        // from the host
+        let etc = self.rootdir.join("etc");
+        for (name, content) in [("passwd", passwd), ("group", group)] {
+            let path = etc.join(name);
+            if self.dry_run(|| Action::Generate { path: path.clone() }) {
+                continue;
+            }
+            fs::create_dir_all(&etc)
+                .unwrap_or_else(|err| panic!("failed to create {}: {}", &etc.display(), err));
+            fs::write(&path, content)
+                .unwrap_or_else(|err| panic!("failed to write {}: {}", &path.display(), err));
+        }
+    }
+
+    fn bind_mount_direntry(&self, entry: &fs::DirEntry) -> io::Result<()> {
+        let path = entry.path();
+        if self.excludes.contains(&path) {
+            return Ok(());
+        }
+        // usually comes for free from readdir(3); like symlink_metadata it
+        // does not follow symlinks, and std falls back to lstat(2) when the
+        // filesystem does not report the type
+        let file_type = entry
+            .file_type()
+            .unwrap_or_else(|err| panic!("cannot get file type of {}: {}", path.display(), err));
+        if file_type.is_dir() {
+            self.bind_mount_directory(entry)?;
+        } else if file_type.is_file() {
+            self.bind_mount_file(entry)?;
+        } else if file_type.is_char_device()
+            || file_type.is_block_device()
+            || file_type.is_fifo()
+            || file_type.is_socket()
+        {
+            // device nodes, fifos and sockets can be bind mounted over a
+            // regular placeholder file. Some kernels refuse to bind mount
+            // sockets, in which case only a warning is printed.
+            self.bind_mount_file(entry)?;
+        } else if file_type.is_symlink() {
+            self.mirror_symlink(entry);
+        }
+        Ok(())
+    }
+
+    /// Makes the root directory the new root of our mount namespace and
+    /// detaches the old root, so that it cannot be reached anymore.
+    fn pivot_root(&self) -> io::Result<()> {
+        // pivot_root(2) fails if the new root or its parent mount is shared
+        set_propagation("/", self.propagation.initial_flag())?;
+        // ... and the new root has to be a mount point
+        mount(
+            Some(&self.rootdir),
+            &self.rootdir,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            NONE,
+        )?;
+
+        let put_old = mkdtemp(".old-root.XXXXXX", Some(&self.rootdir))?;
+        unistd::pivot_root(&self.rootdir, &put_old)?;
+        env::set_current_dir("/")?;
+
+        let old_root = Path::new("/").join(put_old.strip_prefix(&self.rootdir).unwrap());
+        umount2(&old_root, MntFlags::MNT_DETACH)?;
+        fs::remove_dir(&old_root)?;
+
+        if self.propagation == Propagation::Shared {
+            set_propagation("/", MsFlags::MS_SHARED)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the actions that `prepare` would take to assemble the chroot,
+    /// without changing anything on disk. Fails on the problems with
+    /// explicit mounts that [`strict_mounts`](Self::strict_mounts) makes
+    /// fatal.
+    pub fn plan(&self, nixdir: &Path) -> io::Result<Vec<Action>> {
+        let plan = Arc::new(Mutex::new(Plan::new(&self.rootdir)));
+        // the explicit mounts are only recorded, so they must not count as
+        // made for a later enter() on this builder
+        let chroot = Self {
+            plan: Some(plan.clone()),
+            mounted: Arc::default(),
+            ..self.clone()
+        };
+        let res = chroot.mount_all(nixdir);
+        drop(chroot);
+        res?;
+
+        let mut plan = plan.lock().unwrap();
+        Ok(mem::take(&mut plan.actions))
+    }
+
+    /// Mirrors the entries of `/` into the root directory, except for
+    /// `/nix`, the nix directory and excluded paths. Fails if restricting a
+    /// mirrored entry with [`nosuid`](Self::nosuid) and the like does.
+    fn mirror_root(&self, nixdir: &Path) -> io::Result<()> {
+        // Create the parents of excluded paths, so that the mirroring below
+        // recurses into them instead of bind mounting them as a whole.
+        // Excludes below another one are covered by that already, and
+        // creating their parents would bring it back as an empty directory.
+        for path in &self.excludes {
+            if path
+                .ancestors()
+                .skip(1)
+                .any(|parent| self.excludes.contains(parent))
+            {
+                continue;
+            }
+            let excluded = self.rootdir.join(path.strip_prefix("/").unwrap_or(path));
+            let recorded = self.dry_run(|| Action::Exclude {
+                path: excluded.clone(),
+            });
+            if recorded {
+                continue;
+            }
+            if let Some(parent) = excluded.parent() {
+                fs::create_dir_all(parent).unwrap_or_else(|err| {
+                    panic!("failed to create {}: {}", &parent.display(), err)
+                });
+            }
+        }
+
+        // bind the rest of / stuff into rootdir
+        let nix_root = PathBuf::from("/");
+        let dir = fs::read_dir(&nix_root).expect("failed to list /nix directory");
+        let nixdir = fs::canonicalize(nixdir).ok();
+        let entries = dir
+            .map(|entry| entry.expect("error while listing from /nix directory"))
+            // do not bind mount an existing nix installation, nor the nix
+            // directory a second time. Symlinks to it are left out as well,
+            // as their target is not mirrored.
+            .filter(|entry| entry.file_name() != OsStr::new("nix"))
+            .filter(|entry| nixdir.is_none() || fs::canonicalize(entry.path()).ok() != nixdir)
+            .filter(|entry| self.sys != SysMount::Hidden || entry.file_name() != OsStr::new("sys"))
+            .filter(|entry| match &self.include_roots {
+                Some(names) => names.contains(&entry.file_name()),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        // The top-level entries do not overlap, so their (possibly deep)
+        // subtrees can be mirrored in parallel. Everything before and after
+        // this depends on the order and stays serial. The current thread
+        // works the queue as well: after unsharing the PID namespace the
+        // kernel refuses new threads until the first fork, in which case
+        // spawning helpers fails and the mirroring just runs serially.
+        let threads = self
+            .mirror_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let helpers = threads.min(entries.len()).saturating_sub(1);
+        let queue = Mutex::new(entries.iter());
+        let work = || loop {
+            let entry = match queue.lock().unwrap().next() {
+                Some(entry) => entry,
+                None => return Ok(()),
+            };
+            self.bind_mount_direntry(entry)?;
+        };
+        thread::scope(|scope| {
+            let handles = (0..helpers)
+                .map_while(|_| thread::Builder::new().spawn_scoped(scope, work).ok())
+                .collect::<Vec<_>>();
+            let res = work();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err))
+                })
+                .fold(res, Result::and)
+        })
+    }
+
+    /// Like [`mirror_root`](Self::mirror_root), but with overlays whose
+    /// layers are kept in a tmpfs that is only reachable by the overlays.
+    fn mirror_root_overlaid(&self, nixdir: &Path) -> io::Result<()> {
+        let dir = self.rootdir.join(".overlay-layers");
+        let layers = OverlayLayers {
+            dir: dir.clone(),
+            // ours are below the root directory and are not overlaid
+            mount_points: mount_points()
+                .into_iter()
+                .filter(|path| !path.starts_with(&self.rootdir))
+                .collect(),
+            next: AtomicUsize::new(0),
+            warned: AtomicBool::new(false),
+        };
+        let chroot = Self {
+            overlay_layers: Some(Arc::new(layers)),
+            ..self.clone()
+        };
+        if self.plan.is_some() {
+            return chroot.mirror_root(nixdir);
+        }
+
+        fs::create_dir(&dir)
+            .unwrap_or_else(|err| panic!("failed to create {}: {}", &dir.display(), err));
+        mount(
+            Some("tmpfs"),
+            &dir,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            Some("mode=0700"),
+        )
+        .unwrap_or_else(|err| panic!("failed to mount tmpfs to {}: {}", dir.display(), err));
+        let res = chroot.mirror_root(nixdir);
+        // the overlays hold on to the tmpfs themselves
+        umount2(&dir, MntFlags::MNT_DETACH)
+            .unwrap_or_else(|err| panic!("failed to unmount {}: {}", dir.display(), err));
+        fs::remove_dir(&dir)
+            .unwrap_or_else(|err| panic!("failed to remove {}: {}", dir.display(), err));
+        res
+    }
+
+    fn mount_all(&self, nixdir: &Path) -> io::Result<()> {
+        // create /run/opengl-driver/lib in chroot, to behave like NixOS
+        // (needed for nix pkgs with OpenGL or CUDA support to work)
+        let ogldir = match &self.opengl_source {
+            Some(dir) => dir.clone(),
+            None => nixdir.join("var/nix/opengl-driver/lib"),
+        };
+        if self.opengl && !ogldir.is_dir() && self.opengl_source.is_some() {
+            warn!(
+                "opengl driver directory {} does not exist, not mounting /run/opengl-driver/lib",
+                ogldir.display()
+            );
+        } else if self.opengl && ogldir.is_dir() {
+            let ogl_mount = self.rootdir.join("run/opengl-driver/lib");
+            let recorded = self.dry_run(|| Action::BindDirectory {
+                source: ogldir.clone(),
+                dest: ogl_mount.clone(),
+            });
+            if !recorded {
+                fs::create_dir_all(&ogl_mount).map_err(|e| {
+                    error(
+                        format!("failed to create {}: {}", ogl_mount.display(), e),
+                        e,
+                    )
+                })?;
+                bind_mount_or_warn(&ogldir, &ogl_mount, self.bind_flags(&ogldir))?;
+            }
+        }
+
+        if self.resolv_conf {
+            match fs::canonicalize("/etc/resolv.conf") {
+                Ok(source) => self.mount_explicit(
+                    &ExplicitMount {
+                        source,
+                        dest: PathBuf::from("/etc/resolv.conf"),
+                        readonly: true,
+                        noexec: false,
+                    },
+                    nixdir,
+                )?,
+                Err(e) => self.mount_problem(&format!(
+                    "cannot resolve /etc/resolv.conf: {}, not mounting it",
+                    e
+                ))?,
+            }
+        }
+
+        if self.runtime_dir {
+            match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+                Some(dir) if dir.is_absolute() && dir.is_dir() => self.mount_explicit(
+                    &ExplicitMount {
+                        source: dir.clone(),
+                        dest: dir,
+                        readonly: false,
+                        noexec: false,
+                    },
+                    nixdir,
+                )?,
+                Some(dir) => self.mount_problem(&format!(
+                    "XDG_RUNTIME_DIR {} is not an absolute path to a directory, not mounting it",
+                    dir.display()
+                ))?,
+                None => self.mount_problem("XDG_RUNTIME_DIR is not set, not mounting it")?,
+            }
+        }
+
+        let fuse = Path::new("/dev/fuse");
+        if self.fuse && !fuse.exists() {
+            self.mount_problem("/dev/fuse does not exist on the host, is the fuse module loaded?")?;
+        } else if self.fuse {
+            self.mount_device(fuse, nixdir)?;
+        }
+        for device in &self.devices {
+            self.mount_device(device, nixdir)?;
+        }
+
+        for mount in &self.explicit_mounts {
+            self.mount_explicit(mount, nixdir)?;
+        }
+
+        if self.generate_passwd {
+            self.write_passwd();
+        }
+
+        for (link, target) in &self.symlinks {
+            self.create_symlink(link, target);
+        }
+        for path in &self.mkdirs {
+            self.create_path(path, true);
+        }
+        // the host's home stays a single bind mount, so that the command
+        // can create files in it
+        if let Some(home) = self.home.as_ref().filter(|home| !self.is_mirrored(home)) {
+            self.create_path(home, true);
+        }
+        for path in &self.touches {
+            self.create_path(path, false);
+        }
+
+        if self.default_mounts && self.overlay {
+            self.mirror_root_overlaid(nixdir)?;
+        } else if self.default_mounts {
+            self.mirror_root(nixdir)?;
+        }
+
+        // mount the store
+        let nix_mount = self.rootdir.join("nix");
+        let recorded = self.dry_run(|| Action::BindDirectory {
+            source: nixdir.to_path_buf(),
+            dest: nix_mount.clone(),
+        });
+        if !recorded {
+            if let Err(e) = fs::create_dir(&nix_mount) {
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(error(
+                        format!("failed to create {}: {}", nix_mount.display(), e),
+                        e,
+                    ));
+                }
+            }
+            mount(
+                Some(nixdir),
+                &nix_mount,
+                Some("none"),
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            )
+            .map_err(|err| {
+                error(
+                    format!("failed to bind mount {} to /nix: {}", nixdir.display(), err),
+                    err,
+                )
+            })?;
+        }
+
+        if !self.extra_stores.is_empty() {
+            self.mount_extra_stores(nixdir);
+        }
+
+        if self.sys == SysMount::ReadOnly {
+            self.mount_sys();
+        }
+
+        // last, so that they cover what was mounted before
+        for tmpfs in &self.tmpfs_mounts {
+            self.mount_tmpfs(tmpfs);
+        }
+        Ok(())
+    }
+
+    /// Sets up the uid/gid mappings, mounts everything into the root
+    /// directory and chroots into it, but does not execute anything.
+    ///
+    /// This must be called inside the forked child after it has unshared
+    /// its mount and user namespace (`CLONE_NEWNS | CLONE_NEWUSER`);
+    /// otherwise the mounts and the chroot apply to the caller's namespaces.
+    /// On return the process is inside the chroot in the directory set with
+    /// [`workdir`](Self::workdir), or else in its original working directory
+    /// if that exists inside, or else in `/`.
+    ///
+    /// Fails if the user namespace cannot be set up, if a mount the command
+    /// relies on fails, or if entering the root directory or the working
+    /// directory fails.
+    pub fn prepare(&self, nixdir: &Path) -> io::Result<()> {
+        let cwd = env::current_dir();
+
+        // before mounting anything, as overlay mounts check permissions with
+        // the ids inside, and while the host's /proc is still reachable
+        self.write_id_maps()
+            .map_err(|e| error(format!("failed to set up the user namespace: {}", e), e))?;
+
+        // the bind mounts of the chroot inherit the propagation of their
+        // sources
+        if self.propagation == Propagation::Shared && self.use_chroot {
+            warn!("shared propagation needs pivot_root, falling back to slave with --use-chroot");
+        }
+        set_propagation("/", self.propagation.initial_flag())
+            .map_err(|err| error(format!("failed to set the propagation of /: {}", err), err))?;
+
+        self.mount_all(nixdir)?;
+
+        if self.use_chroot {
+            unistd::chroot(&self.rootdir).map_err(|err| {
+                error(format!("chroot({}): {}", self.rootdir.display(), err), err)
+            })?;
+            env::set_current_dir("/")
+                .map_err(|e| error(format!("cannot change directory to /: {}", e), e))?;
+        } else {
+            self.pivot_root().map_err(|e| {
+                error(
+                    format!(
+                        "pivot_root({}): {}, try again with --use-chroot",
+                        self.rootdir.display(),
+                        e
+                    ),
+                    e,
+                )
+            })?;
+        }
+
+        if let Some(workdir) = &self.workdir {
+            return env::set_current_dir(workdir).map_err(|e| {
+                error(
+                    format!(
+                        "cannot change to working directory {}: {}",
+                        workdir.display(),
+                        e
+                    ),
+                    e,
+                )
+            });
+        }
+
+        // restore cwd, which might not be mirrored
+        if let Err(e) = cwd.and_then(|cwd| {
+            env::set_current_dir(&cwd)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cwd.display(), e)))
+        }) {
+            warn!("cannot restore working directory {}, starting in /", e);
+            env::set_current_dir("/")
+                .map_err(|e| error(format!("cannot change directory to /: {}", e), e))?;
+        }
+        Ok(())
+    }
+
+    /// Returns `<profile base>/<user>/<name>` if it exists inside the
+    /// chroot.
+    fn per_user_profile(&self, name: &str) -> Option<PathBuf> {
+        // a generated /etc/passwd lists the user with its id inside
+        let user = unistd::User::from_uid(self.uid)
+            .ok()
+            .flatten()
+            .or_else(|| unistd::User::from_uid(self.inside_ids().0).ok().flatten())?;
+        let path = self.profile_base.join(user.name).join(name);
+        if path.exists() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Forks a helper that maps the subordinate ids of the calling user
+    /// and its supplementary groups once we have unshared, or returns
+    /// `None` if there is nothing to map beyond the calling user.
+    fn spawn_map_helper(&self) -> Option<MapHelper> {
+        let user = match unistd::User::from_uid(self.uid) {
+            Ok(Some(user)) => user,
+            _ => {
+                warn!("cannot look up user {}, only mapping it", self.uid);
+                return None;
+            }
+        };
+        let (mut subuids, mut subgids) = (None, None);
+        if self.subids {
+            let id = self.uid.as_raw();
+            subuids = idmap::subordinate_ids(Path::new("/etc/subuid"), &user.name, id);
+            subgids = idmap::subordinate_ids(Path::new("/etc/subgid"), &user.name, id);
+            if subuids.is_none() || subgids.is_none() {
+                warn!(
+                    "no subordinate ids for {} in /etc/subuid and /etc/subgid",
+                    user.name
+                );
+                subuids = None;
+                subgids = None;
+            }
+        }
+
+        let (uid, gid) = self.inside_ids();
+        let mut fixed_gids = vec![(gid.as_raw(), self.gid.as_raw())];
+        if self.keep_groups {
+            // only works if /etc/subgid allows them, newgidmap checks that
+            match unistd::getgroups() {
+                Ok(groups) => fixed_gids.extend(
+                    groups
+                        .into_iter()
+                        .map(Gid::as_raw)
+                        .filter(|&group| group != self.gid.as_raw() && group != gid.as_raw())
+                        .map(|group| (group, group)),
+                ),
+                Err(e) => warn!("cannot get supplementary groups: {}", e),
+            }
+        }
+        if subuids.is_none() && fixed_gids.len() == 1 {
+            warn!(
+                "nothing to map with newuidmap/newgidmap, only mapping uid {} and gid {}",
+                self.uid, self.gid
+            );
+            return None;
+        }
+
+        let uid_ranges = idmap::id_ranges(&[(uid.as_raw(), self.uid.as_raw())], subuids);
+        let gid_ranges = idmap::id_ranges(&fixed_gids, subgids);
+        MapHelper::spawn(uid_ranges, gid_ranges)
+            .map_err(|err| warn!("failed to start id mapping helper: {}", err))
+            .ok()
+    }
+
+    /// Forks a helper that bind mounts the files of the namespaces we are
+    /// about to unshare into `dir`, from the mount namespace of the caller.
+    fn spawn_persister(&self, dir: &Path) -> nix::Result<Helper> {
+        let mut names = vec!["user", "mnt"];
+        if self.unshare_net {
+            names.push("net");
+        }
+        if self.unshare_ipc {
+            names.push("ipc");
+        }
+        if self.hostname.is_some() {
+            names.push("uts");
+        }
+        if self.unshare_cgroup {
+            names.push("cgroup");
+        }
+        let dir = dir.to_path_buf();
+        Helper::spawn(move |target| {
+            names.iter().all(|name| {
+                let source = PathBuf::from(format!("/proc/{}/ns/{}", target, name));
+                let dest = dir.join(name);
+                let res = fs::File::create(&dest)
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| {
+                        mount(
+                            Some(&source),
+                            &dest,
+                            None::<&str>,
+                            MsFlags::MS_BIND,
+                            None::<&str>,
+                        )
+                        .map_err(|e| e.to_string())
+                    });
+                if let Err(e) = &res {
+                    eprintln!(
+                        "cannot bind mount {} to {}: {}",
+                        source.display(),
+                        dest.display(),
+                        e
+                    );
+                }
+                res.is_ok()
+            })
+        })
+    }
+
+    fn command(&self, cmd: &str, args: &[String]) -> process::Command {
+        let mut command = process::Command::new(cmd);
+        command.args(args);
+        if self.clear_env {
+            command.env_clear();
+            for key in PRESERVED_ENV {
+                if let Some(value) = env::var_os(key) {
+                    command.env(key, value);
+                }
+            }
+        }
+        if self.profile_path {
+            // multi-user installations install into the default profile
+            let profile = self
+                .per_user_profile("profile")
+                .or_else(|| Some(PathBuf::from(DEFAULT_PROFILE)).filter(|p| p.exists()));
+            if let Some(profile) = profile {
+                let mut paths = vec![profile.join("bin")];
+                if let Some(path) = env::var_os("PATH") {
+                    paths.extend(env::split_paths(&path));
+                }
+                match env::join_paths(paths) {
+                    Ok(path) => {
+                        command.env("PATH", path);
+                    }
+                    Err(e) => warn!("cannot add nix profile to PATH: {}", e),
+                }
+            }
+        }
+        // like NIX_CONF_DIR this can still be overridden with env()
+        if let Some(channels) = self.per_user_profile("channels") {
+            let nixpkgs = channels.join("nixpkgs");
+            if nixpkgs.exists() {
+                let mut nix_path = OsString::from("nixpkgs=");
+                nix_path.push(nixpkgs);
+                command.env("NIX_PATH", nix_path);
+            }
+        }
+        if let Some(home) = &self.home {
+            command.env("HOME", home);
+        }
+        if self.runtime_dir {
+            if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+                command.env("XDG_RUNTIME_DIR", dir);
+            }
+        }
+        command
+            .env("NIX_CONF_DIR", "/nix/etc/nix")
+            .envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+
+    /// Unshares the namespaces and prepares the chroot like
+    /// [`run_chroot`](Self::run_chroot), but returns instead of executing a
+    /// command, so that tests can inspect the result.
+    ///
+    /// Unsharing the user namespace requires a single-threaded process, so
+    /// this is usually called in a freshly forked child. With
+    /// [`unshare_pid`](Self::unshare_pid), only processes forked afterwards
+    /// are inside the new PID namespace.
+    ///
+    /// Fails if unsharing fails, if the namespaces cannot be persisted with
+    /// [`persist_ns`](Self::persist_ns), or if [`prepare`](Self::prepare)
+    /// does.
+    pub fn enter(&self, nixdir: &Path) -> io::Result<()> {
+        let mut flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER;
+        if self.unshare_pid {
+            flags |= CloneFlags::CLONE_NEWPID;
+        }
+        if self.unshare_net {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        if self.unshare_ipc {
+            flags |= CloneFlags::CLONE_NEWIPC;
+        }
+        if self.hostname.is_some() {
+            flags |= CloneFlags::CLONE_NEWUTS;
+        }
+        let map_helper = if self.subids || self.keep_groups {
+            self.spawn_map_helper()
+        } else {
+            None
+        };
+        // bind mounts made after unsharing would only show up inside
+        let persister = match &self.persist_ns {
+            Some(dir) => Some(self.spawn_persister(dir).map_err(|err| {
+                error(
+                    format!("failed to start the helper for --persist-ns: {}", err),
+                    err,
+                )
+            })?),
+            None => None,
+        };
+
+        // the helper sees the pipe closing and exits if this fails
+        unshare(flags).map_err(|err| {
+            let message = match userns_hint(err) {
+                Some(hint) => format!("unshare failed: {}\n{}", err, hint),
+                None => format!("unshare failed: {}", err),
+            };
+            error(message, err)
+        })?;
+
+        if let Some(helper) = map_helper {
+            // newgidmap leaves setgroups(2) allowed, unless it is denied
+            // before the mapping is written
+            if self.setgroups == Some(false) && self.write_setgroups {
+                deny_setgroups();
+            }
+            if !helper.finish() {
+                warn!(
+                    "failed to map ids with newuidmap/newgidmap, only mapping uid {} and gid {}",
+                    self.uid, self.gid
+                );
+            }
+        }
+
+        // separately, so that older kernels (before 4.6) can go on without
+        if self.unshare_cgroup {
+            if let Err(e) = unshare(CloneFlags::CLONE_NEWCGROUP) {
+                warn!(
+                    "failed to unshare the cgroup namespace: {}, keeping the one of the host",
+                    e
+                );
+            }
+        }
+
+        if let Some(persister) = persister {
+            if !persister.finish() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "failed to persist the namespaces in {}, which needs the privileges to mount there",
+                        self.persist_ns.as_ref().unwrap().display()
+                    ),
+                ));
+            }
+        }
+
+        if let Some(hostname) = &self.hostname {
+            if let Err(e) = unistd::sethostname(hostname) {
+                warn!("failed to set hostname to {}: {}", hostname, e);
+            }
+        }
+
+        // a new network namespace starts with a loopback interface that is down
+        if self.unshare_net {
+            if let Err(e) = bring_up_loopback() {
+                warn!("failed to bring up the loopback interface: {}", e);
+            }
+        }
+
+        self.prepare(nixdir)?;
+
+        if self.devpts {
+            if let Err(e) = mount_devpts() {
+                warn!(
+                    "failed to mount /dev/pts: {}, keeping the one of the host",
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the calling user and its primary group to
+    /// [`inside_ids`](Self::inside_ids), unless newuidmap and newgidmap
+    /// have written the maps already.
+    fn write_id_maps(&self) -> io::Result<()> {
+        // e.g. inside another sandbox
+        if !Path::new("/proc/self").is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "/proc is not mounted, so the ids cannot be mapped through /proc/self/uid_map",
+            ));
+        }
+        let (uid, gid) = self.inside_ids();
+        if !has_id_map("uid_map") {
+            write_id_map("uid_map", &format!("{} {} 1", uid, self.uid))?;
+        }
+
+        if !has_id_map("gid_map") {
+            // fixes issue #1 where writing to /proc/self/gid_map fails
+            // see user_namespaces(7) for more documentation
+            if self.write_setgroups {
+                if self.setgroups == Some(true) {
+                    warn!("setgroups can only be allowed if newgidmap maps the groups, denying it");
+                }
+                deny_setgroups();
+            }
+
+            write_id_map("gid_map", &format!("{} {} 1", gid, self.gid)).map_err(|e| {
+                if self.write_setgroups {
+                    return e;
+                }
+                let setgroups = fs::read_to_string("/proc/self/setgroups").unwrap_or_default();
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{}, with setgroups left at {} because of --no-setgroups-deny",
+                        e,
+                        setgroups.trim()
+                    ),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Unshares the mount and user namespace, prepares the chroot and
+    /// replaces the current process with `cmd`.
+    ///
+    /// With a PID namespace the current process forks once more, since only
+    /// the first child created after unsharing becomes pid 1. The current
+    /// process then stays around to wait for that child and exits with its
+    /// status.
+    pub fn run_chroot(&self, nixdir: &Path, cmd: &str, args: &[String]) {
+        if let Err(err) = self.enter(nixdir) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+
+        if self.unshare_pid {
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { child, .. }) => wait_for_init(child),
+                Ok(ForkResult::Child) => {
+                    // SIGKILL cannot be forwarded, e.g. after a timeout, and
+                    // the whole PID namespace goes away with its pid 1
+                    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+                }
+                Err(e) => {
+                    eprintln!("fork failed: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        // has to happen in the pid 1 of the PID namespace to show its processes
+        if self.mount_proc {
+            mount_proc();
+        }
+
+        match self.list_mounts {
+            ListMounts::Off => {}
+            ListMounts::ThenRun => {
+                let _ = print_mounts(&mut io::stderr());
+            }
+            ListMounts::ThenExit => {
+                if let Err(e) = print_mounts(&mut io::stdout()) {
+                    eprintln!("failed to print the mounts: {}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            }
+        }
+
+        if let Some(mode) = self.umask {
+            umask(mode);
+        }
+
+        for hook in &self.pre_exec {
+            let status = self
+                .command("sh", &[String::from("-c"), hook.clone()])
+                .status()
+                .unwrap_or_else(|err| {
+                    eprintln!("failed to run pre-exec hook {}: {}", hook, err);
+                    process::exit(1);
+                });
+            if !status.success() {
+                eprintln!("pre-exec hook {} failed: {}", hook, status);
+                let code = status
+                    .code()
+                    .or_else(|| status.signal().map(|signal| 128 + signal))
+                    .unwrap_or(1);
+                process::exit(code);
+            }
+        }
+
+        let mut command = self.command(cmd, args);
+        let err = command.exec();
+
+        eprintln!("failed to execute {}: {}", &cmd, err);
+        match err.kind() {
+            io::ErrorKind::NotFound if !cmd.contains('/') => {
+                let path = command
+                    .get_envs()
+                    .find(|(key, _)| *key == "PATH")
+                    .map_or_else(
+                        || env::var_os("PATH"),
+                        |(_, value)| value.map(OsStr::to_owned),
+                    )
+                    .unwrap_or_default();
+                eprintln!(
+                    "{} was not found in PATH inside the chroot: {}",
+                    cmd,
+                    path.to_string_lossy()
+                );
+            }
+            io::ErrorKind::NotFound => eprintln!(
+                "{} or the interpreter of its #! line does not exist inside the chroot",
+                cmd
+            ),
+            io::ErrorKind::PermissionDenied => eprintln!(
+                "{} is not executable, or is on a filesystem mounted noexec",
+                cmd
+            ),
+            _ => {}
+        }
+        process::exit(1);
+    }
+}
@@ -0,0 +1,63 @@
+//! Child processes that stay in the namespaces of their parent and act on
+//! it once it has unshared, like mapping ids from outside of its new user
+//! namespace.
+
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, fork, ForkResult, Pid};
+use std::fs;
+use std::io::prelude::*;
+use std::os::unix::io::FromRawFd;
+use std::process;
+
+/// A forked child that waits for its parent to unshare and then runs a
+/// task on the parent's pid.
+pub struct Helper {
+    pid: Pid,
+    pipe: fs::File,
+}
+
+impl Helper {
+    /// Forks the helper, which runs `task` with the pid of the caller once
+    /// [`finish`](Self::finish) is called. This has to happen before
+    /// unsharing, so that the helper keeps the old namespaces.
+    pub fn spawn<F: FnOnce(Pid) -> bool>(task: F) -> nix::Result<Self> {
+        let (read_end, write_end) = unistd::pipe()?;
+        let target = unistd::getpid();
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                let _ = unistd::close(read_end);
+                Ok(Self {
+                    pid: child,
+                    pipe: unsafe { fs::File::from_raw_fd(write_end) },
+                })
+            }
+            ForkResult::Child => {
+                let _ = unistd::close(write_end);
+                let mut pipe = unsafe { fs::File::from_raw_fd(read_end) };
+                // EOF without a byte means that unsharing failed
+                let mut buf = [0u8];
+                if !matches!(pipe.read(&mut buf), Ok(1)) {
+                    process::exit(1);
+                }
+                process::exit(if task(target) { 0 } else { 1 });
+            }
+        }
+    }
+
+    /// Lets the helper run its task and waits for it. Returns whether the
+    /// task succeeded.
+    pub fn finish(mut self) -> bool {
+        if self.pipe.write_all(b"x").is_err() {
+            return false;
+        }
+        drop(self.pipe);
+        loop {
+            match waitpid(self.pid, None) {
+                Ok(WaitStatus::Exited(_, status)) => return status == 0,
+                Ok(WaitStatus::Signaled(..)) => return false,
+                Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+                Err(_) => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Mapping ranges of subordinate ids into the user namespace with the
+//! setuid helpers newuidmap(1) and newgidmap(1).
+
+use crate::helper::Helper;
+use crate::warn;
+use nix::unistd::Pid;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// One line of `/proc/<pid>/uid_map` or `/proc/<pid>/gid_map`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+/// Looks up the first range of subordinate ids of the user `name` with id
+/// `id` in a file in the format of `/etc/subuid`. Returns the first id and
+/// the number of ids.
+pub fn subordinate_ids(file: &Path, name: &str, id: u32) -> Option<(u32, u32)> {
+    let content = fs::read_to_string(file).ok()?;
+    content.lines().find_map(|line| {
+        let mut fields = line.trim().split(':');
+        let owner = fields.next()?;
+        let start = fields.next()?.parse().ok()?;
+        let count = fields.next()?.parse().ok()?;
+        if (owner == name || owner.parse() == Ok(id)) && count > 0 {
+            Some((start, count))
+        } else {
+            None
+        }
+    })
+}
+
+/// Maps each `(inside, outside)` pair of `fixed` and fills the free ids
+/// from 0 upwards with the subordinate ids `start..start + count`, if any.
+pub fn id_ranges(fixed: &[(u32, u32)], subordinate: Option<(u32, u32)>) -> Vec<IdRange> {
+    let mut ranges = fixed
+        .iter()
+        .map(|&(inside, outside)| IdRange {
+            inside,
+            outside,
+            count: 1,
+        })
+        .collect::<Vec<_>>();
+    let (mut outside, mut remaining) = match subordinate {
+        Some(subordinate) => subordinate,
+        None => return ranges,
+    };
+
+    let mut taken = fixed.iter().map(|&(inside, _)| inside).collect::<Vec<_>>();
+    taken.sort_unstable();
+    taken.dedup();
+    let mut inside = 0;
+    for next in taken {
+        let count = next.saturating_sub(inside).min(remaining);
+        if count > 0 {
+            ranges.push(IdRange {
+                inside,
+                outside,
+                count,
+            });
+            outside += count;
+            remaining -= count;
+        }
+        inside = inside.max(next + 1);
+    }
+    if remaining > 0 {
+        ranges.push(IdRange {
+            inside,
+            outside,
+            count: remaining,
+        });
+    }
+    ranges
+}
+
+fn run_helper(helper: &str, pid: Pid, ranges: &[IdRange]) -> bool {
+    let mut command = process::Command::new(helper);
+    command.arg(pid.to_string());
+    for range in ranges {
+        command.args([
+            range.inside.to_string(),
+            range.outside.to_string(),
+            range.count.to_string(),
+        ]);
+    }
+    match command.status() {
+        Ok(status) => status.success(),
+        Err(e) => {
+            warn!("failed to run {}: {}", helper, e);
+            false
+        }
+    }
+}
+
+/// A child process that writes the id mappings of its parent once the
+/// parent has unshared its user namespace.
+pub struct MapHelper(Helper);
+
+impl MapHelper {
+    /// Forks the helper. This has to happen before unsharing, as the
+    /// helpers have to run in the parent user namespace.
+    pub fn spawn(uid_ranges: Vec<IdRange>, gid_ranges: Vec<IdRange>) -> nix::Result<Self> {
+        Helper::spawn(move |target| {
+            run_helper("newuidmap", target, &uid_ranges)
+                && run_helper("newgidmap", target, &gid_ranges)
+        })
+        .map(Self)
+    }
+
+    /// Lets the helper write the mappings and waits for it. Returns whether
+    /// both mappings were written.
+    pub fn finish(self) -> bool {
+        self.0.finish()
+    }
+}
@@ -1 +1,22 @@
+// user and mount namespaces only exist on Linux, elsewhere the build would
+// fail deep inside our use of nix and libc instead
+#[cfg(not(target_os = "linux"))]
+compile_error!("nix-user-chroot only works on Linux, as it relies on user and mount namespaces");
+
+#[cfg(target_os = "linux")]
+pub mod chroot;
+#[cfg(target_os = "linux")]
+pub mod helper;
+#[cfg(target_os = "linux")]
+pub mod idmap;
+#[cfg(target_os = "linux")]
 pub mod mkdtemp;
+#[cfg(target_os = "linux")]
+pub mod quiet;
+#[cfg(target_os = "linux")]
+pub mod signals;
+
+#[cfg(target_os = "linux")]
+pub use chroot::{
+    userns_hint, Action, ExplicitMount, ListMounts, Propagation, RunChroot, SysMount, TmpfsMount,
+};
@@ -1,252 +1,1086 @@
-use nix::mount::{mount, MsFlags};
-use nix::sched::{unshare, CloneFlags};
+use nix::errno::Errno;
 use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::Mode;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::{self, fork, ForkResult};
+use nix::unistd::{self, fork, AccessFlags, ForkResult, Gid, Uid};
+use nix_user_chroot::{
+    mkdtemp, quiet, signals, warn, ExplicitMount, ListMounts, Propagation, RunChroot, SysMount,
+    TmpfsMount,
+};
 use std::env;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
-use std::io::prelude::*;
-use std::os::unix::fs::symlink;
-use std::os::unix::process::CommandExt;
-use std::path::{Path, PathBuf};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
 use std::process;
 use std::string::String;
 
-mod mkdtemp;
+/// Removes the temporary chroot root when dropped, so that it does not leak
+/// if we bail out early or panic.
+struct TempDirGuard {
+    path: PathBuf,
+}
 
-const NONE: Option<&'static [u8]> = None;
+impl TempDirGuard {
+    fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
 
-fn bind_mount(source: &Path, dest: &Path) {
-    if let Err(e) = mount(
-        Some(source),
-        dest,
-        Some("none"),
-        MsFlags::MS_BIND | MsFlags::MS_REC | MsFlags::MS_PRIVATE,
-        NONE,
-    ) {
-        eprintln!(
-            "failed to bind mount {} to {}: {}",
-            source.display(),
-            dest.display(),
-            e
-        );
+    /// Disarms the guard so that the directory is not removed.
+    fn keep(self) {
+        mem::forget(self);
     }
 }
 
-pub struct RunChroot<'a> {
-    rootdir: &'a Path,
+impl Drop for TempDirGuard {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("cannot remove tempdir {}: {}", self.path.display(), err);
+            }
+        }
+    }
 }
 
-impl<'a> RunChroot<'a> {
-    fn new(rootdir: &'a Path) -> Self {
-        Self { rootdir }
+/// Exit status after `--timeout`, like timeout(1).
+const TIMED_OUT: i32 = 124;
+
+fn wait_for_child(
+    tempdir: Option<TempDirGuard>,
+    child_pid: unistd::Pid,
+    timeout: Option<u32>,
+) -> ! {
+    if let Err(e) = signals::forward_to(child_pid) {
+        warn!("failed to install signal handlers: {}", e);
+    }
+    if let Some(seconds) = timeout {
+        // without it the command could run forever
+        if let Err(e) = signals::kill_after(seconds) {
+            eprintln!("failed to set up the timeout: {}", e);
+            let _ = kill(child_pid, Signal::SIGKILL);
+            let _ = waitpid(child_pid, None);
+            drop(tempdir);
+            process::exit(1);
+        }
+    }
+
+    let mut exit_status = 1;
+    let mut killed_by = None;
+    loop {
+        match waitpid(
+            child_pid,
+            Some(WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED),
+        ) {
+            Ok(WaitStatus::Stopped(child, _)) => {
+                // stop as well so that our shell notices, and resume the
+                // child once we get continued
+                let _ = kill(unistd::getpid(), Signal::SIGSTOP);
+                let _ = kill(child, Signal::SIGCONT);
+            }
+            Ok(WaitStatus::Continued(_)) => {}
+            Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) if signals::timed_out() => {
+                eprintln!(
+                    "the command was still running after --timeout {}, terminated it",
+                    timeout.unwrap_or_default()
+                );
+                exit_status = TIMED_OUT;
+                break;
+            }
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                // like shells do, in case re-raising the signal does not
+                // terminate us
+                exit_status = 128 + signal as i32;
+                killed_by = Some(signal);
+                break;
+            }
+            Ok(WaitStatus::Exited(_, status)) => {
+                exit_status = status;
+                break;
+            }
+            Ok(what) => {
+                warn!("unexpected wait event happend: {:?}", what);
+                break;
+            }
+            // we got interrupted by a signal, the child is still running
+            Err(Errno::EINTR) => {}
+            Err(e) => {
+                eprintln!("waitpid failed: {}", e);
+                break;
+            }
+        };
+    }
+
+    // process::exit does not run destructors, and neither does dying from
+    // the re-raised signal
+    drop(tempdir);
+
+    if let Some(signal) = killed_by {
+        signals::reraise(signal);
     }
+    process::exit(exit_status);
+}
 
-    fn bind_mount_directory(&self, entry: &fs::DirEntry) {
-        let mountpoint = self.rootdir.join(entry.file_name());
+struct Options {
+    tmpdir: Option<PathBuf>,
+    rootdir: Option<PathBuf>,
+    workdir: Option<PathBuf>,
+    home: Option<PathBuf>,
+    unshare_pid: bool,
+    mount_proc: Option<bool>,
+    unshare_net: bool,
+    unshare_ipc: bool,
+    unshare_cgroup: bool,
+    sys: SysMount,
+    propagation: Propagation,
+    resolv_conf: bool,
+    runtime_dir: bool,
+    fuse: bool,
+    devices: Vec<PathBuf>,
+    strict_mounts: bool,
+    quiet: bool,
+    nosuid: bool,
+    nodev: bool,
+    hostname: Option<String>,
+    devpts: bool,
+    pre_exec: Vec<String>,
+    umask: Option<Mode>,
+    timeout: Option<u32>,
+    dry_run: bool,
+    list_mounts: ListMounts,
+    mounts: Vec<ExplicitMount>,
+    tmpfs: Vec<TmpfsMount>,
+    symlinks: Vec<(PathBuf, PathBuf)>,
+    mkdirs: Vec<PathBuf>,
+    touches: Vec<PathBuf>,
+    excludes: Vec<PathBuf>,
+    include_roots: Vec<String>,
+    default_mounts: bool,
+    overlay: bool,
+    env: Vec<(String, String)>,
+    clear_env: bool,
+    profile_path: bool,
+    profile_base: Option<PathBuf>,
+    generate_passwd: bool,
+    opengl: bool,
+    opengl_source: Option<PathBuf>,
+    extra_stores: Vec<PathBuf>,
+    use_chroot: bool,
+    verify_store: bool,
+    subids: bool,
+    keep_groups: bool,
+    setgroups: Option<bool>,
+    setgroups_deny: bool,
+    persist_ns: Option<PathBuf>,
+    map_root: bool,
+    map_uid: Option<u32>,
+    map_gid: Option<u32>,
+    nixdir: String,
+    cmd: String,
+    args: Vec<String>,
+}
 
-        // if the destination doesn't exist we can proceed as normal
-        if !mountpoint.exists() {
-            if let Err(e) = fs::create_dir(&mountpoint) {
-                if e.kind() != io::ErrorKind::AlreadyExists {
-                    panic!("failed to create {}: {}", &mountpoint.display(), e);
+fn usage(prog: &str) -> ! {
+    eprintln!(
+        "Usage: {} [options] <nixpath> [<command>]\n\n\
+         Without a command, an interactive login shell is started.\n\n\
+         Options:\n  \
+           --tmpdir <dir>  create the chroot root below <dir> instead of $TMPDIR or /tmp\n  \
+           --rootdir <dir> use the existing <dir> as chroot root and keep it afterwards\n  \
+           --shell <shell> the shell to start without a command instead of $SHELL\n  \
+           --workdir <dir> start the command in <dir> inside the chroot\n  \
+           --home <dir>    set HOME to <dir>, creating it inside the chroot if needed\n  \
+           --unshare-pid   run the command as pid 1 of a new PID namespace\n  \
+           --mount-proc    mount a fresh /proc (default with --unshare-pid)\n  \
+           --no-mount-proc keep the /proc of the host\n  \
+           --unshare-net   run the command without network access\n  \
+           --unshare-ipc   isolate System V IPC objects and POSIX message queues\n  \
+           --unshare-cgroup\n                  \
+           show the cgroup of the command as the root of the cgroup hierarchy\n  \
+           --sys <mode>    mirror the host's /sys (host), make it read-only (ro) or hide it (none)\n  \
+           --propagation <mode>\n                  \
+           let host mounts show up inside (slave, shared) or not (private, the default)\n  \
+           --resolv-conf   bind mount the file /etc/resolv.conf points to on the host\n  \
+           --runtime-dir   bind mount $XDG_RUNTIME_DIR with the sockets of your session\n  \
+           --fuse          bind mount /dev/fuse even if /dev is not mirrored\n  \
+           --device <path> bind mount the device node <path> below /dev, can be repeated\n                  \
+           and end in a glob like /dev/snd/*\n  \
+           --hostname <name>\n                  \
+           set the hostname inside the chroot to <name>\n  \
+           --devpts        mount a new /dev/pts instance for programs allocating ptys\n  \
+           --pre-exec <cmd> run the shell command <cmd> inside the chroot first\n  \
+           --umask <mode>  set the umask of the command to the octal <mode>, e.g. 022\n  \
+           --timeout <seconds>\n                  \
+           terminate the command after <seconds> and exit with status 124\n  \
+           --dry-run       print what would be mounted instead of running the command\n  \
+           --list-mounts   print the mounts of the finished chroot instead of running the command\n  \
+           --list-mounts-and-run\n                  \
+           print the mounts of the finished chroot to stderr, then run the command\n  \
+           --bind <src>:<dst>\n                  \
+           bind mount the host path <src> to <dst> inside the chroot\n  \
+           --bind-ro <src>:<dst>\n                  \
+           like --bind, but read-only\n  \
+           --bind-noexec <src>:<dst>\n                  \
+           like --bind, but files in it cannot be executed\n  \
+           --nosuid        ignore setuid bits of everything bind mounted from the host\n  \
+           --nodev         forbid device nodes in everything bind mounted from the host but /dev\n  \
+           --strict-mounts exit instead of skipping or warning about a bind mount\n  \
+           --quiet         do not print warnings, only errors that stop the command from running\n  \
+           --tmpfs <dst>[:<size>]\n                  \
+           mount an empty tmpfs to <dst> inside the chroot, e.g. /tmp:512m\n  \
+           --symlink <link>:<target>\n                  \
+           create a symlink <link> pointing to <target> inside the chroot\n  \
+           --mkdir <path>  create an empty directory <path> inside the chroot\n  \
+           --touch <path>  create an empty file <path> inside the chroot\n  \
+           --exclude <path> do not mirror <path> from the host into the chroot\n  \
+           --include-root <name>\n                  \
+           only mirror the top-level directory /<name> of the host, can be repeated\n  \
+           --no-default-mounts\n                  \
+           mirror nothing from the host, only /nix and what is added explicitly\n  \
+           --overlay       keep changes to mirrored directories in memory instead of on the host\n  \
+           --env <key>=<value>\n                  \
+           set an environment variable for the command\n  \
+           --clearenv      only pass PATH, TERM, HOME and variables from --env to the command\n  \
+           --no-profile-path\n                  \
+           do not prepend the bin directory of the nix profile to PATH\n  \
+           --profile-base <dir>\n                  \
+           look up per-user profiles in <dir>/<user> instead of /nix/var/nix/profiles/per-user\n  \
+           --generate-passwd\n                  \
+           use an /etc/passwd and /etc/group that only contain the current user\n  \
+           --no-opengl     do not mount /run/opengl-driver/lib\n  \
+           --opengl-source <dir>\n                  \
+           mount <dir> to /run/opengl-driver/lib instead of the one in <nixpath>\n  \
+           --use-chroot    enter the root with chroot(2) instead of pivot_root(2)\n  \
+           --no-verify-store\n                  \
+           do not check that <nixpath> contains a nix store\n  \
+           --extra-store <dir>\n                  \
+           also make the store paths in <dir>/store available, can be repeated\n  \
+           --map-subids    also map the ids from /etc/subuid and /etc/subgid\n  \
+           --keep-groups   keep supplementary groups allowed by /etc/subgid\n  \
+           --setgroups <allow|deny>\n                  \
+           whether setgroups(2) is allowed inside, by default only with --map-subids\n  \
+           --no-setgroups-deny\n                  \
+           leave /proc/self/setgroups as inherited, e.g. when nested in another user namespace\n  \
+           --persist-ns <dir>\n                  \
+           bind mount the new namespaces to files in <dir> for nsenter(1), needs mount privileges\n  \
+           --map-root      appear as root inside the chroot\n  \
+           --map-uid <uid> appear as user <uid> inside the chroot\n  \
+           --map-gid <gid> appear with primary group <gid> inside the chroot\n",
+        prog
+    );
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args();
+    let prog = args
+        .next()
+        .unwrap_or_else(|| String::from("nix-user-chroot"));
+
+    let mut tmpdir = None;
+    let mut rootdir = None;
+    let mut workdir = None;
+    let mut home = None;
+    let mut shell = None;
+    let mut unshare_pid = false;
+    let mut mount_proc = None;
+    let mut unshare_net = false;
+    let mut unshare_ipc = false;
+    let mut unshare_cgroup = false;
+    let mut sys = SysMount::Host;
+    let mut propagation = Propagation::Private;
+    let mut resolv_conf = false;
+    let mut runtime_dir = false;
+    let mut fuse = false;
+    let mut devices = Vec::new();
+    let mut strict_mounts = false;
+    let mut quiet = false;
+    let mut nosuid = false;
+    let mut nodev = false;
+    let mut hostname = None;
+    let mut devpts = false;
+    let mut pre_exec = Vec::new();
+    let mut umask = None;
+    let mut timeout = None;
+    let mut dry_run = false;
+    let mut list_mounts = ListMounts::Off;
+    let mut mounts = Vec::new();
+    let mut tmpfs = Vec::new();
+    let mut symlinks = Vec::new();
+    let mut mkdirs = Vec::new();
+    let mut touches = Vec::new();
+    let mut excludes = Vec::new();
+    let mut include_roots = Vec::new();
+    let mut default_mounts = true;
+    let mut overlay = false;
+    let mut env = Vec::new();
+    let mut clear_env = false;
+    let mut profile_path = true;
+    let mut profile_base = None;
+    let mut generate_passwd = false;
+    let mut opengl = true;
+    let mut opengl_source = None;
+    let mut extra_stores = Vec::new();
+    let mut use_chroot = false;
+    let mut verify_store = true;
+    let mut subids = false;
+    let mut keep_groups = false;
+    let mut setgroups = None;
+    let mut setgroups_deny = true;
+    let mut persist_ns = None;
+    let mut map_root = false;
+    let mut map_uid = None;
+    let mut map_gid = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tmpdir" => {
+                let dir = args.next().unwrap_or_else(|| usage(&prog));
+                tmpdir = Some(PathBuf::from(dir));
+            }
+            "--rootdir" => {
+                let dir = args.next().unwrap_or_else(|| usage(&prog));
+                rootdir = Some(PathBuf::from(dir));
+            }
+            "--shell" => shell = Some(args.next().unwrap_or_else(|| usage(&prog))),
+            "--workdir" => {
+                let dir = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if !dir.is_absolute() {
+                    eprintln!(
+                        "invalid argument to --workdir: {} is not an absolute path",
+                        dir.display()
+                    );
+                    usage(&prog);
                 }
+                workdir = Some(dir);
             }
-
-            bind_mount(&entry.path(), &mountpoint)
-        } else {
-            // otherwise, if the dest is also a dir, we can recurse into it
-            // and mount subdirectory siblings of existing paths
-            if mountpoint.is_dir() {
-                let dir = fs::read_dir(entry.path()).unwrap_or_else(|err| {
-                    panic!("failed to list dir {}: {}", entry.path().display(), err)
+            "--home" => {
+                let dir = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if !dir.is_absolute() {
+                    eprintln!(
+                        "invalid argument to --home: {} is not an absolute path",
+                        dir.display()
+                    );
+                    usage(&prog);
+                }
+                home = Some(dir);
+            }
+            "--unshare-pid" => unshare_pid = true,
+            "--mount-proc" => mount_proc = Some(true),
+            "--no-mount-proc" => mount_proc = Some(false),
+            "--unshare-net" => unshare_net = true,
+            "--unshare-ipc" => unshare_ipc = true,
+            "--unshare-cgroup" => unshare_cgroup = true,
+            "--sys" => match args.next().as_deref() {
+                Some("host") => sys = SysMount::Host,
+                Some("ro") => sys = SysMount::ReadOnly,
+                Some("none") => sys = SysMount::Hidden,
+                _ => {
+                    eprintln!("invalid argument to --sys: expected host, ro or none");
+                    usage(&prog);
+                }
+            },
+            "--propagation" => match args.next().as_deref() {
+                Some("private") => propagation = Propagation::Private,
+                Some("slave") => propagation = Propagation::Slave,
+                Some("shared") => propagation = Propagation::Shared,
+                _ => {
+                    eprintln!(
+                        "invalid argument to --propagation: expected private, slave or shared"
+                    );
+                    usage(&prog);
+                }
+            },
+            "--resolv-conf" => resolv_conf = true,
+            "--runtime-dir" => runtime_dir = true,
+            "--fuse" => fuse = true,
+            "--device" => {
+                let path = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if let Err(err) = check_device(&path) {
+                    eprintln!("invalid argument to --device: {}", err);
+                    usage(&prog);
+                }
+                devices.push(path);
+            }
+            "--strict-mounts" => strict_mounts = true,
+            "--quiet" => quiet = true,
+            "--nosuid" => nosuid = true,
+            "--nodev" => nodev = true,
+            "--hostname" => {
+                let name = args.next().unwrap_or_else(|| usage(&prog));
+                // the kernel limit, see gethostname(2)
+                if name.is_empty() || name.len() > 64 {
+                    eprintln!(
+                        "invalid argument to --hostname: {} is not between 1 and 64 bytes long",
+                        name
+                    );
+                    usage(&prog);
+                }
+                hostname = Some(name);
+            }
+            "--devpts" => devpts = true,
+            "--pre-exec" => {
+                pre_exec.push(args.next().unwrap_or_else(|| usage(&prog)));
+            }
+            "--umask" => {
+                let mode = args.next().unwrap_or_else(|| usage(&prog));
+                let bits = u32::from_str_radix(&mode, 8)
+                    .ok()
+                    .and_then(|bits| Mode::from_bits(bits as libc::mode_t))
+                    .filter(|bits| bits.bits() <= 0o777);
+                match bits {
+                    Some(bits) => umask = Some(bits),
+                    None => {
+                        eprintln!(
+                            "invalid argument to --umask: {} is not an octal mode like 022",
+                            mode
+                        );
+                        usage(&prog);
+                    }
+                }
+            }
+            "--timeout" => {
+                let seconds = args.next().unwrap_or_else(|| usage(&prog));
+                match seconds.parse() {
+                    Ok(seconds) if seconds > 0 => timeout = Some(seconds),
+                    _ => {
+                        eprintln!(
+                            "invalid argument to --timeout: {} is not a positive number of seconds",
+                            seconds
+                        );
+                        usage(&prog);
+                    }
+                }
+            }
+            "--dry-run" => dry_run = true,
+            "--list-mounts" => list_mounts = ListMounts::ThenExit,
+            "--list-mounts-and-run" => list_mounts = ListMounts::ThenRun,
+            "--bind" | "--bind-ro" | "--bind-noexec" => {
+                let spec = args.next().unwrap_or_else(|| usage(&prog));
+                let readonly = arg == "--bind-ro";
+                let noexec = arg == "--bind-noexec";
+                let mount = parse_bind(&spec, readonly, noexec).unwrap_or_else(|err| {
+                    eprintln!("invalid argument to {}: {}", arg, err);
+                    usage(&prog)
                 });
-
-                let child = RunChroot::new(&mountpoint);
-                for entry in dir {
-                    let entry = entry.expect("error while listing subdir");
-                    child.bind_mount_direntry(&entry);
+                mounts.push(mount);
+            }
+            "--tmpfs" => {
+                let spec = args.next().unwrap_or_else(|| usage(&prog));
+                let mount = parse_tmpfs(&spec).unwrap_or_else(|err| {
+                    eprintln!("invalid argument to --tmpfs: {}", err);
+                    usage(&prog)
+                });
+                tmpfs.push(mount);
+            }
+            "--symlink" => {
+                let spec = args.next().unwrap_or_else(|| usage(&prog));
+                let symlink = parse_symlink(&spec).unwrap_or_else(|err| {
+                    eprintln!("invalid argument to --symlink: {}", err);
+                    usage(&prog)
+                });
+                symlinks.push(symlink);
+            }
+            "--mkdir" | "--touch" => {
+                let path = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if !path.is_absolute() || path.parent().is_none() {
+                    eprintln!(
+                        "invalid argument to {}: {} is not an absolute path below /",
+                        arg,
+                        path.display()
+                    );
+                    usage(&prog);
+                }
+                if arg == "--mkdir" {
+                    mkdirs.push(path);
+                } else {
+                    touches.push(path);
+                }
+            }
+            "--exclude" => {
+                let path = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if !path.is_absolute() || path.parent().is_none() {
+                    eprintln!(
+                        "invalid argument to --exclude: {} is not an absolute path below /",
+                        path.display()
+                    );
+                    usage(&prog);
+                }
+                excludes.push(path);
+            }
+            "--include-root" => {
+                let name = args.next().unwrap_or_else(|| usage(&prog));
+                let name = name.strip_prefix('/').unwrap_or(&name);
+                if name.is_empty() || name.contains('/') {
+                    eprintln!(
+                        "invalid argument to --include-root: {} is not an entry of /",
+                        name
+                    );
+                    usage(&prog);
+                }
+                include_roots.push(name.to_owned());
+            }
+            "--env" => {
+                let spec = args.next().unwrap_or_else(|| usage(&prog));
+                let var = parse_env(&spec).unwrap_or_else(|err| {
+                    eprintln!("invalid argument to --env: {}", err);
+                    usage(&prog)
+                });
+                env.push(var);
+            }
+            "--no-default-mounts" => default_mounts = false,
+            "--overlay" => overlay = true,
+            "--clearenv" => clear_env = true,
+            "--no-profile-path" => profile_path = false,
+            "--profile-base" => {
+                let dir = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if !dir.is_absolute() {
+                    eprintln!(
+                        "invalid argument to --profile-base: {} is not an absolute path",
+                        dir.display()
+                    );
+                    usage(&prog);
+                }
+                profile_base = Some(dir);
+            }
+            "--generate-passwd" => generate_passwd = true,
+            "--no-opengl" => opengl = false,
+            "--use-chroot" => use_chroot = true,
+            "--no-verify-store" => verify_store = false,
+            "--map-subids" => subids = true,
+            "--keep-groups" => keep_groups = true,
+            "--setgroups" => match args.next().as_deref() {
+                Some("allow") => setgroups = Some(true),
+                Some("deny") => setgroups = Some(false),
+                _ => {
+                    eprintln!("invalid argument to --setgroups: expected allow or deny");
+                    usage(&prog);
+                }
+            },
+            "--no-setgroups-deny" => setgroups_deny = false,
+            "--persist-ns" => {
+                let dir = PathBuf::from(args.next().unwrap_or_else(|| usage(&prog)));
+                if !dir.is_dir() {
+                    eprintln!(
+                        "invalid argument to --persist-ns: {} is not a directory",
+                        dir.display()
+                    );
+                    usage(&prog);
+                }
+                persist_ns = Some(dir);
+            }
+            "--map-root" => map_root = true,
+            "--map-uid" | "--map-gid" => {
+                let id = args.next().unwrap_or_else(|| usage(&prog));
+                // (u32)-1 is the invalid id, see user_namespaces(7)
+                let id = match id.parse() {
+                    Ok(id) if id != u32::MAX => id,
+                    _ => {
+                        eprintln!("invalid argument to {}: {} is not a valid id", arg, id);
+                        usage(&prog);
+                    }
+                };
+                if arg == "--map-uid" {
+                    map_uid = Some(id);
+                } else {
+                    map_gid = Some(id);
                 }
             }
+            "--opengl-source" => {
+                let dir = args.next().unwrap_or_else(|| usage(&prog));
+                opengl_source = Some(PathBuf::from(dir));
+            }
+            "--extra-store" => {
+                let dir = args.next().unwrap_or_else(|| usage(&prog));
+                extra_stores.push(PathBuf::from(dir));
+            }
+            "--" => {
+                positional.extend(args.by_ref());
+            }
+            opt if opt.starts_with("--") => {
+                eprintln!("unknown option: {}", opt);
+                usage(&prog);
+            }
+            _ => {
+                positional.push(arg);
+                positional.extend(args.by_ref());
+            }
         }
     }
 
-    fn bind_mount_file(&self, entry: &fs::DirEntry) {
-        let mountpoint = self.rootdir.join(entry.file_name());
-        if mountpoint.exists() {
-            return;
-        }
-        fs::File::create(&mountpoint)
-            .unwrap_or_else(|err| panic!("failed to create {}: {}", &mountpoint.display(), err));
+    if positional.is_empty() {
+        usage(&prog);
+    }
+    if positional.len() == 1 {
+        positional.push(shell.unwrap_or_else(default_shell));
+        positional.push(String::from("-l"));
+    }
+    let mut positional = positional.into_iter();
+    Options {
+        tmpdir,
+        rootdir,
+        workdir,
+        home,
+        unshare_pid,
+        mount_proc,
+        unshare_net,
+        unshare_ipc,
+        unshare_cgroup,
+        sys,
+        propagation,
+        resolv_conf,
+        runtime_dir,
+        fuse,
+        devices,
+        strict_mounts,
+        quiet,
+        nosuid,
+        nodev,
+        hostname,
+        devpts,
+        pre_exec,
+        umask,
+        timeout,
+        dry_run,
+        list_mounts,
+        mounts,
+        tmpfs,
+        symlinks,
+        mkdirs,
+        touches,
+        excludes,
+        include_roots,
+        default_mounts,
+        overlay,
+        env,
+        clear_env,
+        profile_path,
+        profile_base,
+        generate_passwd,
+        opengl,
+        opengl_source,
+        extra_stores,
+        use_chroot,
+        verify_store,
+        subids,
+        keep_groups,
+        setgroups,
+        setgroups_deny,
+        persist_ns,
+        map_root,
+        map_uid,
+        map_gid,
+        nixdir: positional.next().unwrap(),
+        cmd: positional.next().unwrap(),
+        args: positional.collect(),
+    }
+}
 
-        bind_mount(&entry.path(), &mountpoint)
+/// `$SHELL`, or else `bash` if it is in `PATH`, or else `sh`. / is mirrored,
+/// so what is in the `PATH` of the host is usually found inside as well.
+fn default_shell() -> String {
+    if let Some(shell) = env::var("SHELL").ok().filter(|shell| !shell.is_empty()) {
+        return shell;
     }
+    let has_bash = env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join("bash").is_file()));
+    String::from(if has_bash { "bash" } else { "sh" })
+}
 
-    fn mirror_symlink(&self, entry: &fs::DirEntry) {
-        let link_path = self.rootdir.join(entry.file_name());
-        if link_path.exists() {
-            return;
-        }
-        let path = entry.path();
-        let target = fs::read_link(&path)
-            .unwrap_or_else(|err| panic!("failed to resolve symlink {}: {}", &path.display(), err));
-        symlink(&target, &link_path).unwrap_or_else(|_| {
-            panic!(
-                "failed to create symlink {} -> {}",
-                &link_path.display(),
-                &target.display()
-            )
-        });
-    }
-
-    fn bind_mount_direntry(&self, entry: &fs::DirEntry) {
-        let path = entry.path();
-        let stat = entry
-            .metadata()
-            .unwrap_or_else(|err| panic!("cannot get stat of {}: {}", path.display(), err));
-
-        if stat.is_dir() {
-            self.bind_mount_directory(entry);
-        } else if stat.is_file() {
-            self.bind_mount_file(entry);
-        } else if stat.file_type().is_symlink() {
-            self.mirror_symlink(entry);
+fn parse_bind(spec: &str, readonly: bool, noexec: bool) -> Result<ExplicitMount, String> {
+    let (source, dest) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected <src>:<dst>, got {}", spec))?;
+    let (source, dest) = (PathBuf::from(source), PathBuf::from(dest));
+    if !source.is_absolute() {
+        return Err(format!(
+            "source {} is not an absolute path",
+            source.display()
+        ));
+    }
+    if !dest.is_absolute() {
+        return Err(format!(
+            "destination {} is not an absolute path",
+            dest.display()
+        ));
+    }
+    if dest.parent().is_none() {
+        return Err(String::from("cannot bind mount over /"));
+    }
+    Ok(ExplicitMount {
+        source,
+        dest,
+        readonly,
+        noexec,
+    })
+}
+
+fn parse_tmpfs(spec: &str) -> Result<TmpfsMount, String> {
+    let (dest, size) = match spec.split_once(':') {
+        Some((dest, size)) => (PathBuf::from(dest), Some(size)),
+        None => (PathBuf::from(spec), None),
+    };
+    if !dest.is_absolute() {
+        return Err(format!(
+            "destination {} is not an absolute path",
+            dest.display()
+        ));
+    }
+    if dest.parent().is_none() {
+        return Err(String::from("cannot mount a tmpfs over /"));
+    }
+    if let Some(size) = size {
+        // what tmpfs(5) accepts for size=
+        let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+        if digits.is_empty()
+            || size.len() - digits.len() > 1
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(format!(
+                "expected a size like 512m, 2g or 10%, got {}",
+                size
+            ));
         }
     }
+    Ok(TmpfsMount {
+        dest,
+        size: size.map(str::to_owned),
+    })
+}
 
-    fn run_chroot(&self, nixdir: &Path, cmd: &str, args: &[String]) {
-        let cwd = env::current_dir().expect("cannot get current working directory");
+fn parse_symlink(spec: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (link, target) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected <link>:<target>, got {}", spec))?;
+    let link = PathBuf::from(link);
+    if !link.is_absolute() {
+        return Err(format!("link {} is not an absolute path", link.display()));
+    }
+    if link.parent().is_none() {
+        return Err(String::from("cannot replace / with a symlink"));
+    }
+    if target.is_empty() {
+        return Err(String::from("the target must not be empty"));
+    }
+    Ok((link, PathBuf::from(target)))
+}
 
-        let uid = unistd::getuid();
-        let gid = unistd::getgid();
+fn parse_env(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected <key>=<value>, got {}", spec)),
+    }
+}
 
-        unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER).expect("unshare failed");
+fn check_tmpdir(dir: &Path) {
+    if !dir.is_dir() {
+        eprintln!("temporary directory {} does not exist", dir.display());
+        process::exit(1);
+    }
+    if let Err(e) = unistd::access(dir, AccessFlags::W_OK | AccessFlags::X_OK) {
+        eprintln!(
+            "temporary directory {} is not writable: {}",
+            dir.display(),
+            e
+        );
+        process::exit(1);
+    }
+}
 
-        // create /run/opengl-driver/lib in chroot, to behave like NixOS
-        // (needed for nix pkgs with OpenGL or CUDA support to work)
-        let ogldir = nixdir.join("var/nix/opengl-driver/lib");
-        if ogldir.is_dir() {
-            let ogl_mount = self.rootdir.join("run/opengl-driver/lib");
-            fs::create_dir_all(&ogl_mount)
-                .unwrap_or_else(|err| panic!("failed to create {}: {}", &ogl_mount.display(), err));
-            bind_mount(&ogldir, &ogl_mount);
+/// Resolves the `kind` directory `dir` given on the command line, or exits
+/// if it does not exist or is something else.
+fn resolve_dir(kind: &str, dir: &Path) -> PathBuf {
+    let resolved = fs::canonicalize(dir).unwrap_or_else(|err| {
+        if err.kind() == io::ErrorKind::NotFound {
+            eprintln!("{} {} does not exist", kind, dir.display());
+        } else {
+            eprintln!("failed to resolve {} {}: {}", kind, dir.display(), err);
         }
+        process::exit(1);
+    });
+    if !resolved.is_dir() {
+        eprintln!("{} {} is not a directory", kind, dir.display());
+        process::exit(1);
+    }
+    resolved
+}
 
-        // bind the rest of / stuff into rootdir
-        let nix_root = PathBuf::from("/");
-        let dir = fs::read_dir(&nix_root).expect("failed to list /nix directory");
-        for entry in dir {
-            let entry = entry.expect("error while listing from /nix directory");
-            // do not bind mount an existing nix installation
-            if entry.file_name() == OsStr::new("nix") {
-                continue;
-            }
-            self.bind_mount_direntry(&entry);
-        }
+/// Whether `name` looks like the name of a store path, `<hash>-<name>`.
+fn is_store_path_name(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    name.len() > 33
+        && name[32] == b'-'
+        && name[..32]
+            .iter()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase())
+}
 
-        // mount the store
-        let nix_mount = self.rootdir.join("nix");
-        fs::create_dir(&nix_mount)
-            .unwrap_or_else(|err| panic!("failed to create {}: {}", &nix_mount.display(), err));
-        mount(
-            Some(nixdir),
-            &nix_mount,
-            Some("none"),
-            MsFlags::MS_BIND | MsFlags::MS_REC,
-            NONE,
-        )
-        .unwrap_or_else(|err| panic!("failed to bind mount {} to /nix: {}", nixdir.display(), err));
-
-        // chroot
-        unistd::chroot(self.rootdir)
-            .unwrap_or_else(|err| panic!("chroot({}): {}", self.rootdir.display(), err));
-
-        env::set_current_dir("/").expect("cannot change directory to /");
-
-        // fixes issue #1 where writing to /proc/self/gid_map fails
-        // see user_namespaces(7) for more documentation
-        if let Ok(mut file) = fs::File::create("/proc/self/setgroups") {
-            let _ = file.write_all(b"deny");
+/// Catches passing a profile such as ~/.nix-profile or a channel, which
+/// resolve to somewhere inside a store, instead of the directory holding
+/// the store. With `verify_store`, the directory also has to contain a
+/// store unless it is empty, i.e. nix is about to be installed into it.
+fn check_nixdir(dir: &Path, verify_store: bool) {
+    let store_path = dir.ancestors().find(|path| {
+        path.parent().and_then(Path::file_name) == Some(OsStr::new("store"))
+            && path.file_name().is_some_and(is_store_path_name)
+    });
+    if let Some(store_path) = store_path {
+        eprintln!(
+            "nix directory {} is inside the nix store path {}; pass the directory containing \
+             store/ instead, e.g. ~/.nix, not a profile like ~/.nix-profile",
+            dir.display(),
+            store_path.display()
+        );
+        process::exit(1);
+    }
+    if verify_store && !dir.join("store").is_dir() {
+        let empty = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none());
+        if !empty {
+            eprintln!(
+                "nix directory {} does not contain a nix store; pass the directory nix is \
+                 installed to, an empty one to install nix to, or use --no-verify-store",
+                dir.display()
+            );
+            process::exit(1);
         }
+    }
+}
 
-        let mut uid_map =
-            fs::File::create("/proc/self/uid_map").expect("failed to open /proc/self/uid_map");
-        uid_map
-            .write_all(format!("{} {} 1", uid, uid).as_bytes())
-            .expect("failed to write new uid mapping to /proc/self/uid_map");
-
-        let mut gid_map =
-            fs::File::create("/proc/self/gid_map").expect("failed to open /proc/self/gid_map");
-        gid_map
-            .write_all(format!("{} {} 1", gid, gid).as_bytes())
-            .expect("failed to write new gid mapping to /proc/self/gid_map");
+fn check_device(path: &Path) -> Result<(), String> {
+    if !path.starts_with("/dev")
+        || path == Path::new("/dev")
+        || path.components().any(|c| c == Component::ParentDir)
+    {
+        return Err(format!("{} is not a path below /dev", path.display()));
+    }
+    // only the last component is expanded
+    let dir = path.parent().unwrap_or(path);
+    if dir.as_os_str().as_bytes().iter().any(|c| b"*?".contains(c)) {
+        return Err(format!(
+            "{} contains a glob before its last component",
+            path.display()
+        ));
+    }
+    Ok(())
+}
 
-        // restore cwd
-        env::set_current_dir(&cwd)
-            .unwrap_or_else(|_| panic!("cannot restore working directory {}", cwd.display()));
+/// Whether `name` matches the glob `pattern` with the wildcards `*` and `?`.
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
 
-        let err = process::Command::new(cmd)
-            .args(args)
-            .env("NIX_CONF_DIR", "/nix/etc/nix")
-            .exec();
+/// Expands a glob in the last component of a `--device`, like the shell
+/// would: hidden entries only match a pattern starting with a dot.
+fn expand_device(pattern: &Path) -> Vec<PathBuf> {
+    let (dir, name) = match (pattern.parent(), pattern.file_name()) {
+        (Some(dir), Some(name)) if name.as_bytes().iter().any(|c| b"*?".contains(c)) => {
+            (dir, name.as_bytes())
+        }
+        _ => return vec![pattern.to_path_buf()],
+    };
+    let mut devices = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .filter(|entry| {
+            let entry = entry.as_bytes();
+            (entry.first() != Some(&b'.') || name.first() == Some(&b'.'))
+                && glob_matches(name, entry)
+        })
+        .map(|entry| dir.join(entry))
+        .collect::<Vec<_>>();
+    if devices.is_empty() {
+        warn!("no device matches {}, skipping it", pattern.display());
+    }
+    devices.sort();
+    devices
+}
 
-        eprintln!("failed to execute {}: {}", &cmd, err);
+fn resolve_extra_store(dir: &Path) -> PathBuf {
+    let dir = fs::canonicalize(dir).unwrap_or_else(|err| {
+        eprintln!("failed to resolve extra store {}: {}", dir.display(), err);
+        process::exit(1);
+    });
+    if !dir.join("store").is_dir() {
+        eprintln!(
+            "extra store {} does not contain a nix store; pass the directory containing store/",
+            dir.display()
+        );
+        process::exit(1);
+    }
+    // separators in the options of the overlay mount
+    if dir.as_os_str().as_bytes().iter().any(|c| b",:".contains(c)) {
+        eprintln!(
+            "extra store {} cannot be mounted, its path contains ',' or ':'",
+            dir.display()
+        );
         process::exit(1);
     }
+    dir
 }
 
-fn wait_for_child(rootdir: &Path, child_pid: unistd::Pid) -> ! {
-    let mut exit_status = 1;
-    loop {
-        match waitpid(child_pid, Some(WaitPidFlag::WUNTRACED)) {
-            Ok(WaitStatus::Signaled(child, Signal::SIGSTOP, _)) => {
-                let _ = kill(unistd::getpid(), Signal::SIGSTOP);
-                let _ = kill(child, Signal::SIGCONT);
-            }
-            Ok(WaitStatus::Signaled(_, signal, _)) => {
-                kill(unistd::getpid(), signal).unwrap_or_else(|err| {
-                    panic!("failed to send {} signal to our self: {}", signal, err)
-                });
-            }
-            Ok(WaitStatus::Exited(_, status)) => {
-                exit_status = status;
-                break;
-            }
-            Ok(what) => {
-                eprintln!("unexpected wait event happend: {:?}", what);
-                break;
-            }
-            Err(e) => {
-                eprintln!("waitpid failed: {}", e);
-                break;
-            }
-        };
-    }
+fn main() {
+    let opts = parse_args();
+    quiet::set_quiet(opts.quiet);
 
-    fs::remove_dir_all(rootdir)
-        .unwrap_or_else(|err| panic!("cannot remove tempdir {}: {}", rootdir.display(), err));
+    // resolved before creating the root, as check_nixdir may exit
+    let nixdir = resolve_dir("nix directory", Path::new(&opts.nixdir));
+    check_nixdir(&nixdir, opts.verify_store);
+    let extra_stores = opts
+        .extra_stores
+        .iter()
+        .map(|dir| resolve_extra_store(dir))
+        .collect::<Vec<_>>();
 
-    process::exit(exit_status);
-}
+    let (rootdir, tempdir) = match &opts.rootdir {
+        Some(dir) => (resolve_dir("root directory", dir), None),
+        None => {
+            let tmpdir = opts.tmpdir.clone().unwrap_or_else(env::temp_dir);
+            check_tmpdir(&tmpdir);
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <nixpath> <command>\n", args[0]);
-        process::exit(1);
-    }
+            let rootdir = mkdtemp::mkdtemp("nix-chroot.XXXXXX", Some(&tmpdir))
+                .unwrap_or_else(|err| panic!("failed to create temporary directory: {}", err));
+            // removing the mount points would unmount them in the persisted
+            // mount namespace as well
+            if opts.persist_ns.is_some() {
+                warn!(
+                    "keeping {} as the root of the persisted mount namespace",
+                    rootdir.display()
+                );
+                (rootdir, None)
+            } else {
+                (rootdir.clone(), Some(TempDirGuard::new(rootdir)))
+            }
+        }
+    };
 
-    let rootdir = mkdtemp::mkdtemp("nix-chroot.XXXXXX")
-        .unwrap_or_else(|err| panic!("failed to create temporary directory: {}", err));
+    let mut chroot = RunChroot::new(&rootdir)
+        .unshare_pid(opts.unshare_pid)
+        .mount_proc(opts.mount_proc.unwrap_or(opts.unshare_pid))
+        .unshare_net(opts.unshare_net)
+        .unshare_ipc(opts.unshare_ipc)
+        .unshare_cgroup(opts.unshare_cgroup)
+        .sys(opts.sys)
+        .propagation(opts.propagation)
+        .default_mounts(opts.default_mounts)
+        .overlay(opts.overlay)
+        .resolv_conf(opts.resolv_conf)
+        .runtime_dir(opts.runtime_dir)
+        .fuse(opts.fuse)
+        .strict_mounts(opts.strict_mounts)
+        .nosuid(opts.nosuid)
+        .nodev(opts.nodev)
+        .list_mounts(opts.list_mounts)
+        .clear_env(opts.clear_env)
+        .profile_path(opts.profile_path)
+        .generate_passwd(opts.generate_passwd)
+        .opengl(opts.opengl)
+        .use_chroot(opts.use_chroot)
+        .subids(opts.subids)
+        .keep_groups(opts.keep_groups)
+        .write_setgroups(opts.setgroups_deny)
+        .map_root(opts.map_root)
+        .devpts(opts.devpts);
+    if let Some(uid) = opts.map_uid {
+        chroot = chroot.map_uid(Uid::from_raw(uid));
+    }
+    if let Some(gid) = opts.map_gid {
+        chroot = chroot.map_gid(Gid::from_raw(gid));
+    }
+    if let Some(allow) = opts.setgroups {
+        chroot = chroot.setgroups(allow);
+    }
+    if let Some(dir) = &opts.persist_ns {
+        chroot = chroot.persist_ns(dir);
+    }
+    if let Some(dir) = &opts.workdir {
+        chroot = chroot.workdir(dir);
+    }
+    if let Some(dir) = &opts.home {
+        chroot = chroot.home(dir);
+    }
+    if let Some(name) = &opts.hostname {
+        chroot = chroot.hostname(name);
+    }
+    if let Some(dir) = &opts.profile_base {
+        chroot = chroot.profile_base(dir);
+    }
+    if let Some(dir) = &opts.opengl_source {
+        chroot = chroot.opengl_source(dir);
+    }
+    for dir in &extra_stores {
+        chroot = chroot.extra_store(dir);
+    }
+    for device in opts
+        .devices
+        .iter()
+        .flat_map(|pattern| expand_device(pattern))
+    {
+        chroot = chroot.device(&device);
+    }
+    for mount in opts.mounts {
+        chroot = chroot.explicit_mount(mount);
+    }
+    for mount in opts.tmpfs {
+        chroot = chroot.tmpfs(mount);
+    }
+    for (link, target) in &opts.symlinks {
+        chroot = chroot.symlink(link, target);
+    }
+    for path in &opts.mkdirs {
+        chroot = chroot.mkdir(path);
+    }
+    for path in &opts.touches {
+        chroot = chroot.touch(path);
+    }
+    for path in &opts.excludes {
+        chroot = chroot.exclude(path);
+    }
+    for name in &opts.include_roots {
+        chroot = chroot.include_root(OsStr::new(name));
+    }
+    if let Some(mode) = opts.umask {
+        chroot = chroot.umask(mode);
+    }
+    for hook in &opts.pre_exec {
+        chroot = chroot.pre_exec(hook);
+    }
+    for (key, value) in &opts.env {
+        chroot = chroot.env(key, value);
+    }
 
-    let nixdir = fs::canonicalize(&args[1])
-        .unwrap_or_else(|err| panic!("failed to resolve nix directory {}: {}", &args[1], err));
+    if opts.dry_run {
+        match chroot.plan(&nixdir) {
+            Ok(actions) => {
+                for action in actions {
+                    println!("{}", action);
+                }
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
     match unsafe { fork() } {
-        Ok(ForkResult::Parent { child, .. }) => wait_for_child(&rootdir, child),
-        Ok(ForkResult::Child) => RunChroot::new(&rootdir).run_chroot(&nixdir, &args[2], &args[3..]),
+        Ok(ForkResult::Parent { child, .. }) => wait_for_child(tempdir, child, opts.timeout),
+        Ok(ForkResult::Child) => {
+            // only the parent may clean up: inside our mount namespace the
+            // root is full of bind mounts of host directories
+            if let Some(tempdir) = tempdir {
+                tempdir.keep();
+            }
+            chroot.run_chroot(&nixdir, &opts.cmd, &opts.args)
+        }
         Err(e) => {
             eprintln!("fork failed: {}", e);
         }
@@ -1,26 +1,59 @@
 use nix::errno::Errno;
+use nix::sys::stat::Mode;
+use nix::unistd;
+use std::collections::hash_map::RandomState;
 use std::env;
-use std::ffi::OsString;
-use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
 
-mod ffi {
-    extern "C" {
-        pub fn mkdtemp(template: *mut libc::c_char) -> *mut libc::c_char;
-    }
+const SUFFIX: &str = "XXXXXX";
+const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+/// With 62^6 possible suffixes, running out of attempts means something
+/// else than bad luck is going on.
+pub const ATTEMPTS: u32 = 100;
+
+fn random_suffix(attempt: u32) -> String {
+    // RandomState is seeded from the operating system once per thread and
+    // differs for each instance
+    let mut bits = RandomState::new().hash_one((process::id(), attempt, SystemTime::now()));
+    (0..SUFFIX.len())
+        .map(|_| {
+            let c = CHARS[(bits % CHARS.len() as u64) as usize];
+            bits /= CHARS.len() as u64;
+            c as char
+        })
+        .collect()
+}
+
+/// Creates a unique directory from `template` below `basedir`, falling back
+/// to `env::temp_dir()` (`$TMPDIR` or `/tmp`) when no base is given.
+///
+/// Like mkdtemp(3), the template has to end in `XXXXXX`, which is replaced
+/// with random characters, and the directory is created with mode `0700`.
+pub fn mkdtemp(template: &str, basedir: Option<&Path>) -> nix::Result<PathBuf> {
+    mkdtemp_with(template, basedir, random_suffix)
 }
 
-pub fn mkdtemp(template: &str) -> nix::Result<PathBuf> {
-    let mut tmpdir = env::temp_dir();
-    tmpdir.push(template);
-    let mut buf = tmpdir.into_os_string().into_vec();
-    buf.push(b'\0'); // make a c string
+/// Like [`mkdtemp`], but replaces `XXXXXX` with `suffix(attempt)` instead of
+/// random characters, so that tests can make the attempts collide. Gives
+/// up with `EEXIST` after [`ATTEMPTS`] attempts.
+pub fn mkdtemp_with<F: FnMut(u32) -> String>(
+    template: &str,
+    basedir: Option<&Path>,
+    mut suffix: F,
+) -> nix::Result<PathBuf> {
+    let prefix = template.strip_suffix(SUFFIX).ok_or(Errno::EINVAL)?;
+    let tmpdir = basedir.map_or_else(env::temp_dir, Path::to_path_buf);
 
-    let res = unsafe { ffi::mkdtemp(buf.as_mut_ptr() as *mut libc::c_char) };
-    if res.is_null() {
-        Err(Errno::last())
-    } else {
-        buf.pop(); // strip null byte
-        Ok(PathBuf::from(OsString::from_vec(buf)))
+    for attempt in 0..ATTEMPTS {
+        let path = tmpdir.join(format!("{}{}", prefix, suffix(attempt)));
+        match unistd::mkdir(&path, Mode::S_IRWXU) {
+            Ok(()) => return Ok(path),
+            Err(Errno::EEXIST) => {}
+            Err(e) => return Err(e),
+        }
     }
+    Err(Errno::EEXIST)
 }
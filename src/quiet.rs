@@ -0,0 +1,28 @@
+//! Warnings about what the chroot has to do without, which scripts can
+//! turn off.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Turns the warnings of [`warn!`](crate::warn) off or on again for this
+/// process and its children forked afterwards. Errors that end the process
+/// are printed regardless.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints a warning to stderr like `eprintln!`, unless [`set_quiet`] turned
+/// warnings off.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if !$crate::quiet::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
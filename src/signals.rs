@@ -0,0 +1,98 @@
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Signals that [`forward_to`] passes on to the child.
+pub const FORWARDED: &[Signal] = &[
+    Signal::SIGWINCH,
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+];
+
+/// Seconds between terminating the child with SIGTERM once the time of
+/// [`kill_after`] is up and killing it with SIGKILL.
+pub const GRACE_PERIOD: u32 = 5;
+
+static CHILD: AtomicI32 = AtomicI32::new(0);
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn forward(signo: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Only forward signals sent by other processes (si_code <= 0, see
+    // sigaction(2)). Signals generated by the terminal are sent to its whole
+    // foreground process group, so the child already got its own copy.
+    if unsafe { (*info).si_code } > 0 {
+        return;
+    }
+    let pid = CHILD.load(Ordering::Relaxed);
+    if pid > 0 {
+        unsafe { libc::kill(pid, signo) };
+    }
+}
+
+/// Installs handlers that forward [`FORWARDED`] signals sent to us to
+/// `child`, so that e.g. a `kill` of our pid or a resized terminal reaches
+/// the command running in the chroot.
+pub fn forward_to(child: Pid) -> nix::Result<()> {
+    CHILD.store(child.as_raw(), Ordering::Relaxed);
+    let action = SigAction::new(
+        SigHandler::SigAction(forward),
+        SaFlags::SA_SIGINFO | SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in FORWARDED {
+        unsafe { sigaction(*signal, &action) }?;
+    }
+    Ok(())
+}
+
+/// Kills us with the `signal` the child died from, so that our caller sees
+/// the same. The handlers of [`forward_to`] are reset first, as they would
+/// catch the signal and send it on to the pid of the reaped child instead.
+/// Only returns if the signal does not terminate us, like SIGWINCH.
+pub fn reraise(signal: Signal) {
+    CHILD.store(0, Ordering::Relaxed);
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    // SIGPIPE is ignored by the Rust runtime; SIGKILL cannot be changed
+    for signal in FORWARDED.iter().chain(&[signal]) {
+        let _ = unsafe { sigaction(*signal, &action) };
+    }
+    let _ = unsafe { libc::kill(libc::getpid(), signal as libc::c_int) };
+}
+
+extern "C" fn time_out(_: libc::c_int) {
+    let pid = CHILD.load(Ordering::Relaxed);
+    if pid <= 0 {
+        return;
+    }
+    if TIMED_OUT.swap(true, Ordering::Relaxed) {
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    } else {
+        unsafe {
+            libc::kill(pid, libc::SIGTERM);
+            libc::alarm(GRACE_PERIOD);
+        }
+    }
+}
+
+/// Terminates the child passed to [`forward_to`] with SIGTERM after
+/// `seconds`, and kills it with SIGKILL if it is still around
+/// [`GRACE_PERIOD`] seconds later.
+///
+/// The handler of SIGALRM sends the signals itself, so a blocking
+/// waitpid(2) does not have to check for the timeout.
+pub fn kill_after(seconds: u32) -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(time_out),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGALRM, &action) }?;
+    unsafe { libc::alarm(seconds) };
+    Ok(())
+}
+
+/// Whether the time of [`kill_after`] is up and the child was terminated.
+pub fn timed_out() -> bool {
+    TIMED_OUT.load(Ordering::Relaxed)
+}
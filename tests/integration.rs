@@ -1,16 +1,89 @@
-use nix_user_chroot::mkdtemp;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, getgid, getuid, ForkResult, Pid};
+use nix_user_chroot::idmap::{self, IdRange};
+use nix_user_chroot::{mkdtemp, userns_hint, Action, ExplicitMount, RunChroot};
 use std::env;
+use std::ffi::OsStr;
 use std::fs;
-use std::process::Command;
+use std::io::Write;
+use std::ops::Deref;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const TARGET: &str = env!("TARGET");
 
+/// A path that is removed when the test is done with it, also when an
+/// assertion or `unwrap` fails first.
+struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Creates a fresh directory `<prefix>.XXXXXX` in `/tmp`.
+    fn dir(prefix: &str) -> Self {
+        Self::dir_in(prefix, Path::new("/tmp"))
+    }
+
+    fn dir_in(prefix: &str, basedir: &Path) -> Self {
+        Self(mkdtemp::mkdtemp(&format!("{}.XXXXXX", prefix), Some(basedir)).unwrap())
+    }
+
+    /// Reserves the unique path `<self>.<suffix>` without creating it, for
+    /// files and directories the test makes next to its own.
+    fn sibling(&self, suffix: &str) -> Self {
+        let mut path = self.0.clone().into_os_string();
+        path.push(".");
+        path.push(suffix);
+        Self(PathBuf::from(path))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for TempPath {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_os_str()
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = match fs::symlink_metadata(&self.0) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&self.0),
+            Ok(_) => fs::remove_file(&self.0),
+            Err(_) => Ok(()),
+        };
+    }
+}
+
+fn nix_user_chroot(options: &[&str], nixdir: &Path) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_nix-user-chroot"));
+    cmd.args(options).arg(nixdir);
+    cmd
+}
+
 #[test]
 fn run_nix_install() {
-    let tempdir = mkdtemp::mkdtemp("/tmp/nix.XXXXXX").unwrap();
+    let tempdir = TempPath::dir("nix");
 
     let result = Command::new("cargo")
-        .args(&[
+        .args([
             "run",
             "--target",
             TARGET,
@@ -20,6 +93,1510 @@ fn run_nix_install() {
             "curl https://nixos.org/nix/install | bash",
         ])
         .status();
-    fs::remove_dir_all(tempdir).unwrap();
     assert!(result.unwrap().success());
 }
+
+#[test]
+fn userns_hints_name_the_sysctl() {
+    assert!(userns_hint(Errno::EPERM)
+        .unwrap()
+        .contains("kernel.unprivileged_userns_clone"));
+    assert!(userns_hint(Errno::ENOSPC)
+        .unwrap()
+        .contains("user.max_user_namespaces"));
+    assert!(userns_hint(Errno::EBADF).is_none());
+}
+
+#[test]
+fn mkdtemp_creates_unique_private_dirs() {
+    let basedir = TempPath::dir("mkdtemp");
+
+    let mut dirs = (0..200)
+        .map(|_| mkdtemp::mkdtemp("dir.XXXXXX", Some(&basedir)).unwrap())
+        .collect::<Vec<_>>();
+    for dir in &dirs {
+        let name = dir.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("dir.") && name.len() == "dir.XXXXXX".len());
+        assert_ne!(name, "dir.XXXXXX");
+        let metadata = fs::metadata(dir).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.mode() & 0o777, 0o700);
+    }
+    dirs.sort();
+    dirs.dedup();
+    assert_eq!(dirs.len(), 200);
+
+    let invalid = mkdtemp::mkdtemp("dir.XXX", Some(&basedir));
+    let missing = mkdtemp::mkdtemp("dir.XXXXXX", Some(&basedir.join("missing")));
+    assert_eq!(invalid, Err(Errno::EINVAL));
+    assert_eq!(missing, Err(Errno::ENOENT));
+}
+
+#[test]
+fn mkdtemp_retries_on_collision() {
+    let basedir = TempPath::dir("mkdtemp");
+    fs::create_dir(basedir.join("dir.taken0")).unwrap();
+    fs::create_dir(basedir.join("dir.taken1")).unwrap();
+
+    let mut attempts = 0;
+    let retried = mkdtemp::mkdtemp_with("dir.XXXXXX", Some(&basedir), |attempt| {
+        attempts += 1;
+        format!("taken{}", attempt)
+    });
+    let retries = attempts;
+    attempts = 0;
+    let exhausted = mkdtemp::mkdtemp_with("dir.XXXXXX", Some(&basedir), |_| {
+        attempts += 1;
+        String::from("taken0")
+    });
+    assert_eq!(retried, Ok(basedir.join("dir.taken2")));
+    assert_eq!(retries, 3);
+    assert_eq!(exhausted, Err(Errno::EEXIST));
+    assert_eq!(attempts, mkdtemp::ATTEMPTS);
+}
+
+#[test]
+fn subordinate_ids_surround_own_id() {
+    let subuid = TempPath::dir("subuid");
+    let file = subuid.join("subuid");
+    fs::write(&file, "alice:100000:65536\n1001:200000:1000\n").unwrap();
+    let alice = idmap::subordinate_ids(&file, "alice", 1000);
+    let bob = idmap::subordinate_ids(&file, "bob", 1001);
+    let carol = idmap::subordinate_ids(&file, "carol", 1002);
+
+    assert_eq!(alice, Some((100000, 65536)));
+    assert_eq!(bob, Some((200000, 1000)));
+    assert_eq!(carol, None);
+    assert_eq!(
+        idmap::id_ranges(&[(1000, 1000)], Some((100000, 65536))),
+        [
+            IdRange {
+                inside: 1000,
+                outside: 1000,
+                count: 1
+            },
+            IdRange {
+                inside: 0,
+                outside: 100000,
+                count: 1000
+            },
+            IdRange {
+                inside: 1001,
+                outside: 101000,
+                count: 64536
+            },
+        ]
+    );
+}
+
+#[test]
+fn subordinate_ids_skip_supplementary_groups() {
+    let ranges = idmap::id_ranges(&[(0, 1000), (27, 27), (5, 5)], Some((100000, 30)));
+    let mut inside = ranges
+        .iter()
+        .flat_map(|range| range.inside..range.inside + range.count)
+        .collect::<Vec<_>>();
+    inside.sort_unstable();
+    assert_eq!(inside, (0..33).collect::<Vec<_>>());
+    assert_eq!(ranges.iter().map(|range| range.count).sum::<u32>(), 3 + 30);
+    assert_eq!(idmap::id_ranges(&[(1000, 1000)], None).len(), 1);
+}
+
+#[test]
+fn map_subids_falls_back_to_own_id() {
+    let tempdir = TempPath::dir("nix");
+
+    // unless the test runs with subordinate ids and newuidmap, this only
+    // checks the fallback
+    let output = nix_user_chroot(&["--map-subids"], &tempdir)
+        .args(["id", "-u"])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        nix::unistd::getuid().to_string()
+    );
+}
+
+#[test]
+fn map_root_runs_command_as_root() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--map-root"], &tempdir)
+        .args(["sh", "-c", "id -u && id -g && touch /nix/file"])
+        .output();
+    let owner = fs::metadata(tempdir.join("file")).map(|m| m.uid());
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n0\n");
+    assert_eq!(owner.unwrap(), nix::unistd::getuid().as_raw());
+}
+
+#[test]
+fn setgroups_is_denied_by_default() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&[], &tempdir)
+        .args(["cat", "/proc/self/setgroups"])
+        .output();
+    let invalid = nix_user_chroot(&["--setgroups", "maybe"], &tempdir)
+        .arg("true")
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "deny");
+    assert!(!invalid.unwrap().status.success());
+}
+
+#[test]
+fn inside_ids_can_be_chosen() {
+    let tempdir = TempPath::dir("nix");
+    let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+
+    let maps = |options: &[&str]| {
+        nix_user_chroot(options, &tempdir)
+            .args([
+                "cat",
+                "/proc/self/uid_map",
+                "/proc/self/gid_map",
+                "/proc/self/setgroups",
+            ])
+            .output()
+            .map(|output| {
+                assert!(output.status.success());
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .collect::<Vec<_>>()
+            })
+    };
+    let identity = maps(&[]);
+    let uid_only = maps(&["--map-uid", "1234"]);
+    let gid_only = maps(&["--map-gid", "5678"]);
+    let both = maps(&["--map-uid", "1234", "--map-gid", "5678"]);
+    let root_and_gid = maps(&["--map-root", "--map-gid", "5678"]);
+    let invalid = nix_user_chroot(&["--map-uid", "4294967295"], &tempdir)
+        .arg("true")
+        .output();
+
+    let expected = |inside_uid: u32, inside_gid: u32| {
+        vec![
+            format!("{} {} 1", inside_uid, uid),
+            format!("{} {} 1", inside_gid, gid),
+            String::from("deny"),
+        ]
+    };
+    assert_eq!(identity.unwrap(), expected(uid, gid));
+    assert_eq!(uid_only.unwrap(), expected(1234, gid));
+    assert_eq!(gid_only.unwrap(), expected(uid, 5678));
+    assert_eq!(both.unwrap(), expected(1234, 5678));
+    assert_eq!(root_and_gid.unwrap(), expected(0, 5678));
+    assert!(!invalid.unwrap().status.success());
+}
+
+#[test]
+fn missing_proc_is_reported() {
+    let tempdir = TempPath::dir("nix");
+
+    // like in a sandbox without /proc, which our own user and mount
+    // namespace hides behind an empty tmpfs
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "sh", "-c"])
+        .arg(r#"mount -t tmpfs tmpfs /proc && exec "$0" "$1" true"#)
+        .arg(env!("CARGO_BIN_EXE_nix-user-chroot"))
+        .arg(&tempdir)
+        .output();
+    let output = output.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/proc is not mounted"));
+}
+
+#[test]
+fn setgroups_can_be_left_alone() {
+    let tempdir = TempPath::dir("nix");
+
+    let allowed = nix_user_chroot(&["--no-setgroups-deny"], &tempdir)
+        .arg("true")
+        .output();
+    // an outer user namespace that denied setgroups passes that on
+    let nested = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount"])
+        .arg(env!("CARGO_BIN_EXE_nix-user-chroot"))
+        .arg("--no-setgroups-deny")
+        .arg(&tempdir)
+        .args(["cat", "/proc/self/setgroups"])
+        .output();
+    let allowed = allowed.unwrap();
+    assert_eq!(allowed.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&allowed.stderr)
+        .contains("with setgroups left at allow because of --no-setgroups-deny"));
+    let nested = nested.unwrap();
+    assert!(nested.status.success());
+    assert_eq!(String::from_utf8_lossy(&nested.stdout).trim(), "deny");
+}
+
+#[test]
+fn namespaces_can_be_persisted() {
+    let tempdir = TempPath::dir("nix");
+    let rootdir = tempdir.sibling("root");
+    let nsdir = tempdir.sibling("ns");
+    fs::create_dir(&rootdir).unwrap();
+    fs::create_dir(&nsdir).unwrap();
+    fs::create_dir(tempdir.join("store")).unwrap();
+
+    // bind mounting on the host needs privileges, which an outer user and
+    // mount namespace provides
+    let script = r#"
+        "$0" --rootdir "$1" --persist-ns "$2" --unshare-ipc "$3" true || exit 1
+        ls "$2"
+        nsenter --preserve-credentials --user="$2/user" --mount="$2/mnt" ls /nix
+    "#;
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "sh", "-c", script])
+        .arg(env!("CARGO_BIN_EXE_nix-user-chroot"))
+        .args([&rootdir, &nsdir, &tempdir])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "ipc\nmnt\nuser\nstore\n"
+    );
+}
+
+#[test]
+fn device_nodes_are_reachable() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&[], &tempdir)
+        .args(["sh", "-c", "head -c 16 /dev/zero | wc -c"])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "16");
+}
+
+#[test]
+fn unshare_pid_runs_command_as_pid_1() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--unshare-pid"], &tempdir)
+        .args(["sh", "-c", "echo $$"])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "1");
+}
+
+#[test]
+fn hostname_is_set_in_uts_namespace() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--hostname", "sandbox"], &tempdir)
+        .args(["cat", "/proc/sys/kernel/hostname"])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "sandbox");
+    assert_ne!(
+        fs::read_to_string("/proc/sys/kernel/hostname")
+            .unwrap()
+            .trim(),
+        "sandbox"
+    );
+}
+
+#[test]
+fn unshare_ipc_uses_new_ipc_namespace() {
+    let tempdir = TempPath::dir("nix");
+
+    let host = fs::read_link("/proc/self/ns/ipc").unwrap();
+    let inside = |opts: &[&str]| {
+        let output = nix_user_chroot(opts, &tempdir)
+            .args(["readlink", "/proc/self/ns/ipc"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    let shared = inside(&[]);
+    let unshared = inside(&["--unshare-ipc"]);
+    assert_eq!(Path::new(&shared), host);
+    assert_ne!(Path::new(&unshared), host);
+}
+
+#[test]
+fn unshare_cgroup_hides_host_hierarchy() {
+    let tempdir = TempPath::dir("nix");
+
+    let host = fs::read_link("/proc/self/ns/cgroup").unwrap();
+    let output = nix_user_chroot(&["--unshare-cgroup"], &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "readlink /proc/self/ns/cgroup && cat /proc/self/cgroup",
+        ])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    assert_ne!(Path::new(lines.next().unwrap()), host);
+    assert!(lines.all(|line| line.ends_with(":/")));
+}
+
+#[test]
+fn sys_can_be_read_only_or_hidden() {
+    let tempdir = TempPath::dir("nix");
+
+    let hidden = nix_user_chroot(&["--sys", "none"], &tempdir)
+        .args(["test", "-e", "/sys"])
+        .status();
+    let readonly = nix_user_chroot(&["--sys", "ro"], &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "test -d /sys/class && ! touch /sys/x 2>/dev/null",
+        ])
+        .status();
+    let fresh = nix_user_chroot(&["--sys", "ro", "--unshare-net"], &tempdir)
+        .args(["ls", "/sys/class/net"])
+        .output();
+    assert!(!hidden.unwrap().success());
+    assert!(readonly.unwrap().success());
+    let fresh = fresh.unwrap();
+    assert!(fresh.status.success());
+    assert_eq!(String::from_utf8_lossy(&fresh.stdout).trim(), "lo");
+}
+
+#[test]
+fn devpts_is_a_new_instance() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--devpts"], &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "grep -q ' /dev/pts .*ptmxmode=666' /proc/self/mountinfo && test -c /dev/ptmx",
+        ])
+        .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn pre_exec_hooks_run_first() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(
+        &[
+            "--pre-exec",
+            "echo one > /nix/log",
+            "--pre-exec",
+            "echo two >> /nix/log",
+        ],
+        &tempdir,
+    )
+    .args(["cat", "/nix/log"])
+    .output();
+    let failed = nix_user_chroot(&["--no-verify-store", "--pre-exec", "exit 3"], &tempdir)
+        .args(["touch", "/nix/ran"])
+        .status();
+    let ran = tempdir.join("ran").exists();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "one\ntwo\n");
+    assert_eq!(failed.unwrap().code(), Some(3));
+    assert!(!ran);
+}
+
+#[test]
+fn store_paths_are_rejected_as_nixdir() {
+    let tempdir = TempPath::dir("nix");
+    let env = tempdir.join("store/0123456789abcdfghijklmnpqrsvwxyz-user-environment");
+    fs::create_dir_all(&env).unwrap();
+    symlink(&env, tempdir.join("profile")).unwrap();
+    symlink(&tempdir, tempdir.join("root")).unwrap();
+
+    let output = nix_user_chroot(&[], &tempdir.join("profile"))
+        .arg("true")
+        .output();
+    let linked = nix_user_chroot(&[], &tempdir.join("root"))
+        .arg("true")
+        .status();
+    let output = output.unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is inside the nix store path"));
+    assert!(linked.unwrap().success());
+}
+
+#[test]
+fn nixdir_without_store_is_rejected() {
+    let tempdir = TempPath::dir("nix");
+    fs::create_dir(tempdir.join("var")).unwrap();
+
+    let rejected = nix_user_chroot(&[], &tempdir).arg("true").status();
+    let skipped = nix_user_chroot(&["--no-verify-store"], &tempdir)
+        .arg("true")
+        .status();
+    fs::create_dir(tempdir.join("store")).unwrap();
+    let accepted = nix_user_chroot(&[], &tempdir).arg("true").status();
+    assert!(!rejected.unwrap().success());
+    assert!(skipped.unwrap().success());
+    assert!(accepted.unwrap().success());
+}
+
+#[test]
+fn overlay_keeps_changes_off_the_host() {
+    let tempdir = TempPath::dir("nix");
+    let hostdir = TempPath::dir_in("host", Path::new("/var/tmp"));
+    fs::write(hostdir.join("existing"), "host").unwrap();
+
+    let script = format!(
+        "cd {} && echo changed > existing && touch new && cat existing && ls",
+        hostdir.display()
+    );
+    let output = nix_user_chroot(&["--overlay"], &tempdir)
+        .args(["sh", "-c", &script])
+        .output();
+    let existing = fs::read_to_string(hostdir.join("existing")).unwrap();
+    let created = hostdir.join("new").exists();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "changed\nexisting\nnew\n"
+    );
+    assert_eq!(existing, "host");
+    assert!(!created);
+}
+
+#[test]
+fn extra_stores_are_overlaid_in_order() {
+    let tempdir = TempPath::dir("nix");
+    let shared = tempdir.sibling("shared");
+    let other = tempdir.sibling("other");
+    fs::create_dir_all(tempdir.join("store/own")).unwrap();
+    for (dir, name) in [(&tempdir, "own"), (&shared, "shared"), (&other, "other")] {
+        fs::create_dir_all(dir.join("store/both")).unwrap();
+        fs::write(dir.join("store/both/name"), name).unwrap();
+    }
+    fs::create_dir_all(shared.join("store/only-shared")).unwrap();
+    fs::remove_dir_all(tempdir.join("store/both")).unwrap();
+
+    let output = nix_user_chroot(
+        &[
+            "--extra-store",
+            shared.to_str().unwrap(),
+            "--extra-store",
+            other.to_str().unwrap(),
+        ],
+        &tempdir,
+    )
+    .args([
+        "sh",
+        "-c",
+        "test -d /nix/store/own && test -d /nix/store/only-shared && cat /nix/store/both/name \
+         && mkdir /nix/store/new",
+    ])
+    .output();
+    let created = tempdir.join("store/new").is_dir();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "shared");
+    assert!(created);
+}
+
+#[test]
+fn dry_run_does_not_touch_rootdir() {
+    let rootdir = TempPath::dir("nix-chroot");
+    let nixdir = TempPath::dir("nix");
+
+    let actions = RunChroot::new(&rootdir).plan(&nixdir).unwrap();
+    let untouched = fs::read_dir(&rootdir).unwrap().next().is_none();
+
+    assert!(untouched);
+    assert!(actions.contains(&Action::BindDirectory {
+        source: nixdir.to_path_buf(),
+        dest: PathBuf::from("/nix"),
+    }));
+    assert!(actions.contains(&Action::BindDirectory {
+        source: PathBuf::from("/usr"),
+        dest: PathBuf::from("/usr"),
+    }));
+}
+
+#[test]
+fn no_default_mounts_only_mounts_what_is_requested() {
+    let rootdir = TempPath::dir("nix-chroot");
+    let nixdir = TempPath::dir("nix");
+
+    let actions = RunChroot::new(&rootdir)
+        .default_mounts(false)
+        .opengl(false)
+        .exclude(Path::new("/etc/hostname"))
+        .explicit_mount(ExplicitMount {
+            source: PathBuf::from("/usr"),
+            dest: PathBuf::from("/usr"),
+            readonly: true,
+            noexec: false,
+        })
+        .plan(&nixdir)
+        .unwrap();
+    let untouched = fs::read_dir(&rootdir).unwrap().next().is_none();
+
+    assert!(untouched);
+    assert_eq!(
+        actions,
+        [
+            Action::Explicit {
+                source: PathBuf::from("/usr"),
+                dest: PathBuf::from("/usr"),
+                readonly: true,
+                noexec: false,
+            },
+            Action::BindDirectory {
+                source: nixdir.to_path_buf(),
+                dest: PathBuf::from("/nix"),
+            },
+        ]
+    );
+}
+
+#[test]
+fn nixdir_is_not_mirrored_twice() {
+    let rootdir = TempPath::dir("nix-chroot");
+
+    // on hosts with a merged /usr, /bin is a symlink to the nix directory
+    // here, otherwise the nix directory itself
+    let nixdir = fs::canonicalize("/bin").unwrap();
+    let actions = RunChroot::new(&rootdir).plan(&nixdir).unwrap();
+
+    assert!(actions.contains(&Action::BindDirectory {
+        source: nixdir.clone(),
+        dest: PathBuf::from("/nix"),
+    }));
+    assert!(!actions.iter().any(|action| matches!(
+        action,
+        Action::BindDirectory { dest, .. } | Action::MirrorSymlink { link: dest, .. }
+            if dest == Path::new("/bin")
+    )));
+}
+
+#[test]
+fn enter_prepares_without_exec() {
+    let rootdir = TempPath::dir("nix-chroot");
+    let nixdir = TempPath::dir("nix");
+    fs::write(nixdir.join("marker"), "").unwrap();
+    let chroot = RunChroot::new(&rootdir)
+        .exclude(Path::new("/etc/hostname"))
+        .symlink(Path::new("/etc/link"), Path::new("passwd"));
+
+    // the test harness is multi-threaded, which unsharing a user namespace
+    // does not allow
+    let status = match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let ok = chroot.enter(&nixdir).is_ok()
+                && Path::new("/nix/marker").exists()
+                && !Path::new("/etc/hostname").exists()
+                && fs::read_link("/etc/link").ok() == Some(PathBuf::from("passwd"))
+                && Path::new("/usr").is_dir();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+    };
+    assert!(matches!(status, WaitStatus::Exited(_, 0)));
+}
+
+#[test]
+fn enter_after_plan_makes_explicit_mounts() {
+    let rootdir = TempPath::dir("nix-chroot");
+    let nixdir = TempPath::dir("nix");
+    let source = nixdir.sibling("src");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("marker"), "").unwrap();
+    let chroot = RunChroot::new(&rootdir).explicit_mount(ExplicitMount {
+        source: source.to_path_buf(),
+        dest: PathBuf::from("/opt/bound"),
+        readonly: false,
+        noexec: false,
+    });
+
+    // like benches/prepare.rs, which plans and enters with one builder
+    let planned = chroot
+        .plan(&nixdir)
+        .unwrap()
+        .iter()
+        .any(|action| matches!(action, Action::Explicit { .. }));
+    let status = match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let ok = chroot.enter(&nixdir).is_ok() && Path::new("/opt/bound/marker").exists();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+    };
+    assert!(planned);
+    assert!(matches!(status, WaitStatus::Exited(_, 0)));
+}
+
+#[test]
+fn enter_fails_when_namespaces_cannot_be_persisted() {
+    let rootdir = TempPath::dir("nix-chroot");
+    let nixdir = TempPath::dir("nix");
+    let missing = nixdir.sibling("ns");
+    let chroot = RunChroot::new(&rootdir).persist_ns(&missing);
+
+    let status = match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let failed = chroot.enter(&nixdir).is_err();
+            unsafe { libc::_exit(if failed { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+    };
+    assert!(matches!(status, WaitStatus::Exited(_, 0)));
+}
+
+#[test]
+fn bind_mounts_from_command_line() {
+    let tempdir = TempPath::dir("nix");
+    let datadir = TempPath::dir("data");
+    fs::write(datadir.join("file"), "hello").unwrap();
+
+    let rw = format!("{}:/opt/rw", datadir.display());
+    let ro = format!("{}:/opt/ro", datadir.display());
+    let output = nix_user_chroot(&["--bind", &rw, "--bind-ro", &ro], &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "cat /opt/ro/file && ! touch /opt/ro/new && touch /opt/rw/new",
+        ])
+        .output();
+    let created = datadir.join("new").exists();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello");
+    assert!(created);
+}
+
+#[test]
+fn missing_directories_are_reported() {
+    let tempdir = TempPath::dir("nix");
+    let missing = tempdir.sibling("missing");
+    let file = tempdir.sibling("file");
+    fs::write(&file, "").unwrap();
+
+    let run = |options: &[&str], nixdir: &Path| {
+        let output = nix_user_chroot(options, nixdir).arg("true").output();
+        let output = output.unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    let rootdir = run(&["--rootdir", missing.to_str().unwrap()], &tempdir);
+    let not_dir = run(&["--rootdir", file.to_str().unwrap()], &tempdir);
+    let nixdir = run(&[], &missing);
+    assert!(rootdir.contains(&format!(
+        "root directory {} does not exist",
+        missing.display()
+    )));
+    assert!(not_dir.contains(&format!(
+        "root directory {} is not a directory",
+        file.display()
+    )));
+    assert!(nixdir.contains(&format!(
+        "nix directory {} does not exist",
+        missing.display()
+    )));
+}
+
+#[test]
+fn tmpfs_shadows_mirrored_directory() {
+    let tempdir = TempPath::dir("nix");
+    let shadowed = TempPath::dir("shadowed");
+    fs::write(shadowed.join("file"), "").unwrap();
+
+    let tmpfs = format!("{}:1m", shadowed.display());
+    let output = nix_user_chroot(&["--tmpfs", &tmpfs], &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "test -z \"$(ls -A \"$0\")\" && touch \"$0/scratch\"",
+        ])
+        .arg(&shadowed)
+        .status();
+    let leaked = shadowed.join("scratch").exists();
+    assert!(output.unwrap().success());
+    assert!(!leaked);
+    assert!(shadowed.join("file").exists());
+}
+
+#[test]
+fn symlinks_from_command_line() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--symlink", "/etc/hostname:passwd"], &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "test \"$(readlink /etc/hostname)\" = passwd && grep -q ^root: /etc/hostname",
+        ])
+        .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn mkdir_and_touch_create_paths() {
+    let tempdir = TempPath::dir("nix");
+
+    let options = [
+        "--mkdir",
+        "/var/empty-dir/nested",
+        "--touch",
+        "/etc/hostname",
+        "--touch",
+        "/run/new/file",
+    ];
+    let output = nix_user_chroot(&options, &tempdir)
+        .args([
+            "sh",
+            "-c",
+            "test -d /var/empty-dir/nested && test -d /var/lib \
+             && test -f /etc/hostname && test ! -s /etc/hostname && test -f /run/new/file",
+        ])
+        .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn default_profile_is_added_to_path() {
+    let tempdir = TempPath::dir("nix");
+    fs::create_dir(tempdir.join("store")).unwrap();
+    let bin = tempdir.join("var/nix/profiles/default/bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("hello"), "").unwrap();
+    fs::set_permissions(bin.join("hello"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = nix_user_chroot(&[], &tempdir)
+        .args(["sh", "-c", "command -v hello"])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "/nix/var/nix/profiles/default/bin/hello"
+    );
+}
+
+#[test]
+fn profile_base_is_configurable() {
+    let tempdir = TempPath::dir("nix");
+    let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+        .unwrap()
+        .unwrap();
+    let bin = tempdir.join("profiles").join(user.name).join("profile/bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("hello"), "").unwrap();
+    fs::set_permissions(bin.join("hello"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = nix_user_chroot(
+        &["--no-verify-store", "--profile-base", "/nix/profiles"],
+        &tempdir,
+    )
+    .args(["sh", "-c", "command -v hello"])
+    .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("/nix/profiles/"));
+}
+
+#[test]
+fn bind_mounts_directly_below_root() {
+    let rootdir = TempPath::dir("nix-chroot");
+    let tempdir = TempPath::dir("nix");
+    let config = tempdir.sibling("config");
+    fs::write(&config, "hello").unwrap();
+
+    let bind = format!("{}:/myconfig", config.display());
+    let output = nix_user_chroot(&["--bind", &bind], &tempdir)
+        .args(["cat", "/myconfig"])
+        .output();
+    let actions = RunChroot::new(&rootdir)
+        .explicit_mount(ExplicitMount {
+            source: config.to_path_buf(),
+            dest: PathBuf::from("/"),
+            readonly: false,
+            noexec: false,
+        })
+        .plan(&tempdir)
+        .unwrap();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello");
+    assert!(!actions
+        .iter()
+        .any(|action| matches!(action, Action::Explicit { .. })));
+}
+
+#[test]
+fn strict_mounts_turn_warnings_into_errors() {
+    let tempdir = TempPath::dir("nix");
+    let package = tempdir.join("store/00000000000000000000000000000000-package");
+    fs::create_dir_all(&package).unwrap();
+    symlink("/etc/passwd", package.join("passwd")).unwrap();
+    let escaping = format!("{}:/opt/passwd", package.join("passwd").display());
+    let missing = format!("{}:/opt/missing", package.join("missing").display());
+
+    let run = |options: &[&str]| nix_user_chroot(options, &tempdir).arg("true").output();
+    let lenient = run(&["--bind", &escaping, "--bind", &missing]);
+    let strict_escaping = run(&["--strict-mounts", "--bind", &escaping]);
+    let strict_missing = run(&["--strict-mounts", "--bind", &missing]);
+    let lenient = lenient.unwrap();
+    assert!(lenient.status.success());
+    let stderr = String::from_utf8_lossy(&lenient.stderr);
+    assert!(stderr.contains("resolves to /etc/passwd outside of"));
+    assert!(stderr.contains("does not exist, skipping"));
+    assert_eq!(strict_escaping.unwrap().status.code(), Some(1));
+    assert_eq!(strict_missing.unwrap().status.code(), Some(1));
+}
+
+#[test]
+fn mounts_of_the_chroot_are_listed() {
+    let tempdir = TempPath::dir("nix");
+
+    let listed = nix_user_chroot(&["--list-mounts", "--tmpfs", "/opt"], &tempdir)
+        .args(["echo", "started"])
+        .output();
+    let listed_and_run = nix_user_chroot(&["--list-mounts-and-run"], &tempdir)
+        .args(["echo", "started"])
+        .output();
+    let listed = listed.unwrap();
+    assert!(listed.status.success());
+    let stdout = String::from_utf8_lossy(&listed.stdout);
+    assert!(!stdout.contains("started"));
+    let nix = stdout
+        .lines()
+        .find(|line| line.starts_with("/nix "))
+        .unwrap();
+    assert!(nix.contains(&format!("[{}]", tempdir.display())));
+    assert!(stdout
+        .lines()
+        .any(|line| line.starts_with("/opt ") && line.contains(" tmpfs ")));
+
+    let listed_and_run = listed_and_run.unwrap();
+    assert!(listed_and_run.status.success());
+    assert_eq!(String::from_utf8_lossy(&listed_and_run.stdout), "started\n");
+    assert!(String::from_utf8_lossy(&listed_and_run.stderr)
+        .lines()
+        .any(|line| line.starts_with("/nix ")));
+}
+
+#[test]
+fn nosuid_nodev_and_noexec_restrict_bind_mounts() {
+    let tempdir = TempPath::dir("nix");
+    let bin = tempdir.sibling("bin");
+    fs::create_dir(&bin).unwrap();
+    let script = bin.join("hello");
+    fs::write(&script, "#!/bin/sh\necho hello\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o4755)).unwrap();
+    // reports the options of the mount it runs from
+    let setuid = bin.join("setuid");
+    fs::write(
+        &setuid,
+        "#!/bin/sh\nawk '$5 == \"/opt/bin\" { print $6 }' /proc/self/mountinfo\n",
+    )
+    .unwrap();
+    fs::set_permissions(&setuid, fs::Permissions::from_mode(0o4755)).unwrap();
+    let bind = format!("{}:/opt/bin", bin.display());
+
+    let listed = nix_user_chroot(
+        &["--nosuid", "--nodev", "--bind", &bind, "--list-mounts"],
+        &tempdir,
+    )
+    .output();
+    let nosuid = nix_user_chroot(&["--nosuid", "--bind", &bind], &tempdir)
+        .arg("/opt/bin/setuid")
+        .output();
+    let devices = nix_user_chroot(&["--nodev"], &tempdir)
+        .args(["sh", "-c", "head -c 16 /dev/zero | wc -c"])
+        .output();
+    let executable = nix_user_chroot(&["--bind", &bind], &tempdir)
+        .arg("/opt/bin/hello")
+        .output();
+    let noexec = nix_user_chroot(&["--bind-noexec", &bind], &tempdir)
+        .arg("/opt/bin/hello")
+        .output();
+
+    let listed = listed.unwrap();
+    assert!(listed.status.success());
+    let stdout = String::from_utf8_lossy(&listed.stdout);
+    let options = |mount_point: &str| {
+        let line = stdout
+            .lines()
+            .find(|line| line.split_whitespace().next() == Some(mount_point))
+            .unwrap();
+        String::from(line.split_whitespace().nth(3).unwrap())
+    };
+    // the kernel ignores the setuid bit of files on the mirrored /usr
+    let usr = options("/usr");
+    assert!(usr.split(',').any(|option| option == "nosuid"));
+    assert!(usr.split(',').any(|option| option == "nodev"));
+    assert!(!options("/dev").split(',').any(|option| option == "nodev"));
+    assert!(options("/opt/bin")
+        .split(',')
+        .any(|option| option == "nosuid"));
+
+    // With only our own uid mapped, the setuid bit could not change the
+    // credentials anyway, so this checks that the kernel runs the file from
+    // a mount that ignores the bit.
+    let nosuid = nosuid.unwrap();
+    assert!(nosuid.status.success());
+    assert!(String::from_utf8_lossy(&nosuid.stdout)
+        .trim()
+        .split(',')
+        .any(|option| option == "nosuid"));
+
+    let devices = devices.unwrap();
+    assert!(devices.status.success());
+    assert_eq!(String::from_utf8_lossy(&devices.stdout).trim(), "16");
+    assert_eq!(
+        String::from_utf8_lossy(&executable.unwrap().stdout),
+        "hello\n"
+    );
+    assert_eq!(noexec.unwrap().status.code(), Some(1));
+}
+
+#[test]
+fn host_mounts_propagate_with_slave_propagation() {
+    let tempdir = TempPath::dir("nix");
+    let shared = tempdir.sibling("shared");
+    fs::create_dir(&shared).unwrap();
+
+    // a user and mount namespace of our own stands in for the host, with
+    // a shared mount whose submounts are mounted after the chroot started
+    let script = r#"
+        mount -t tmpfs tmpfs "$1" && mount --make-shared "$1" && mkdir "$1/sub" || exit 1
+        for mode in private slave shared; do
+            "$0" --propagation $mode "$2" sh -c '
+                touch "$0/started"
+                until [ -e "$0/ready" ]; do sleep 0.05; done
+                echo $1 $(ls "$0/sub")
+            ' "$1" $mode &
+            until [ -e "$1/started" ]; do sleep 0.05; done
+            mount -t tmpfs tmpfs "$1/sub" && touch "$1/sub/mounted" "$1/ready"
+            wait
+            umount "$1/sub" && rm "$1/started" "$1/ready"
+        done
+    "#;
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "sh", "-c", script])
+        .arg(env!("CARGO_BIN_EXE_nix-user-chroot"))
+        .args([&shared, &tempdir])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "private\nslave mounted\nshared mounted\n"
+    );
+}
+
+#[test]
+fn fuse_without_device_warns() {
+    let tempdir = TempPath::dir("nix");
+
+    // an outer user and mount namespace stands in for a host without the
+    // fuse module, with an empty /dev
+    let script = r#"
+        mount -t tmpfs tmpfs /dev || exit 1
+        "$0" --fuse "$1" true && echo warned
+        "$0" --fuse --strict-mounts "$1" true || echo failed
+    "#;
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "sh", "-c", script])
+        .arg(env!("CARGO_BIN_EXE_nix-user-chroot"))
+        .arg(&tempdir)
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "warned\nfailed\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr
+            .matches("/dev/fuse does not exist on the host")
+            .count(),
+        2
+    );
+    assert!(stderr.contains("aborting because of --strict-mounts"));
+}
+
+#[test]
+#[ignore = "needs /dev/fuse on the host"]
+fn fuse_device_is_bind_mounted() {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipping, /dev/fuse does not exist on this host");
+        return;
+    }
+    let tempdir = TempPath::dir("nix");
+
+    // leaving out only /dev/fuse makes /dev get mirrored entry by entry
+    let run = |options: &[&str]| {
+        nix_user_chroot(options, &tempdir)
+            .args(["sh", "-c", "test -c /dev/fuse && test -c /dev/null"])
+            .status()
+    };
+    let excluded = run(&["--exclude", "/dev/fuse"]);
+    let fuse = run(&["--exclude", "/dev/fuse", "--fuse"]);
+    let plan = nix_user_chroot(&["--no-default-mounts", "--fuse", "--dry-run"], &tempdir).output();
+    assert!(!excluded.unwrap().success());
+    assert!(fuse.unwrap().success());
+    assert!(String::from_utf8_lossy(&plan.unwrap().stdout)
+        .lines()
+        .any(|line| line.starts_with("EXPLICIT /dev/fuse -> ")));
+}
+
+#[test]
+fn devices_are_bind_mounted() {
+    let tempdir = TempPath::dir("nix");
+
+    let script = "test -c /dev/null && test -c /dev/zero && test ! -e /dev/full";
+    let devices = nix_user_chroot(
+        &[
+            "--exclude",
+            "/dev",
+            "--device",
+            "/dev/nul?",
+            "--device",
+            "/dev/zer*",
+        ],
+        &tempdir,
+    )
+    .args(["sh", "-c", script])
+    .status();
+    let outside = nix_user_chroot(&["--device", "/dev/../etc/passwd"], &tempdir)
+        .arg("true")
+        .output();
+    assert!(devices.unwrap().success());
+    let outside = outside.unwrap();
+    assert!(!outside.status.success());
+    assert!(String::from_utf8_lossy(&outside.stderr).contains("is not a path below /dev"));
+}
+
+#[test]
+fn repeated_devices_are_mounted_once() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(
+        &[
+            "--exclude",
+            "/dev",
+            "--device",
+            "/dev/null",
+            "--device",
+            "/dev/nul?",
+        ],
+        &tempdir,
+    )
+    .args(["grep", "-c", " /dev/null ", "/proc/self/mountinfo"])
+    .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+}
+
+#[test]
+fn quiet_only_prints_errors() {
+    let tempdir = TempPath::dir("nix");
+    let missing = format!("{}:/opt/missing", tempdir.join("missing").display());
+
+    let warned = nix_user_chroot(&["--quiet", "--bind", &missing], &tempdir)
+        .args(["echo", "started"])
+        .output();
+    let failed = nix_user_chroot(&["--quiet"], &tempdir)
+        .arg("/does/not/exist")
+        .output();
+    let warned = warned.unwrap();
+    assert!(warned.status.success());
+    assert_eq!(String::from_utf8_lossy(&warned.stdout), "started\n");
+    assert!(warned.stderr.is_empty());
+    let failed = failed.unwrap();
+    assert!(!failed.status.success());
+    assert!(String::from_utf8_lossy(&failed.stderr).contains("failed to execute /does/not/exist"));
+}
+
+#[test]
+fn timeout_terminates_the_command() {
+    let tempdir = TempPath::dir("nix");
+    let tmpdir = tempdir.sibling("roots");
+    fs::create_dir(&tmpdir).unwrap();
+
+    let run = |options: &[&str]| {
+        let start = Instant::now();
+        let output = nix_user_chroot(options, &tempdir)
+            .args(["sleep", "60"])
+            .output();
+        (output, start.elapsed())
+    };
+    let tmpdir_option = tmpdir.to_str().unwrap();
+    let (terminated, terminated_after) = run(&["--timeout", "1", "--tmpdir", tmpdir_option]);
+    // as pid 1 of a PID namespace, sleep ignores SIGTERM and has to be
+    // killed after the grace period
+    let (killed, killed_after) = run(&["--timeout", "1", "--unshare-pid"]);
+    let roots_left = fs::read_dir(&tmpdir).unwrap().count();
+
+    assert_eq!(terminated.unwrap().status.code(), Some(124));
+    assert!(terminated_after < Duration::from_secs(5));
+    assert_eq!(roots_left, 0);
+    let killed = killed.unwrap();
+    assert_eq!(killed.status.code(), Some(124));
+    assert!(String::from_utf8_lossy(&killed.stderr).contains("still running after --timeout 1"));
+    assert!(killed_after >= Duration::from_secs(6));
+    assert!(killed_after < Duration::from_secs(30));
+}
+
+#[test]
+fn failed_bind_mount_is_fatal() {
+    let tempdir = TempPath::dir("nix");
+    let rootdir = TempPath::dir("nix-chroot");
+    let chroot = RunChroot::new(&rootdir).explicit_mount(ExplicitMount {
+        source: PathBuf::from("/proc/self/ns/mnt"),
+        dest: PathBuf::from("/mntns"),
+        readonly: false,
+        noexec: false,
+    });
+
+    // the kernel refuses to bind mount the file of the mount namespace the
+    // mount happens in
+    let status = match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let failed = chroot.enter(&tempdir).is_err_and(|err| {
+                err.to_string()
+                    .contains("failed to bind mount /proc/self/ns/mnt to /mntns")
+            });
+            unsafe { libc::_exit(if failed { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+    };
+    assert!(matches!(status, WaitStatus::Exited(_, 0)));
+
+    let output = nix_user_chroot(&["--bind", "/proc/self/ns/mnt:/mntns"], &tempdir)
+        .args(["echo", "started"])
+        .output();
+    let output = output.unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("failed to bind mount /proc/self/ns/mnt to /mntns"));
+}
+
+#[test]
+fn resolv_conf_is_bind_mounted() {
+    let tempdir = TempPath::dir("nix");
+    let resolv_conf = tempdir.sibling("resolv.conf");
+    fs::write(&resolv_conf, "nameserver 192.0.2.1\n").unwrap();
+
+    // an outer user and mount namespace stands in for a host whose
+    // /etc/resolv.conf is a symlink, as with systemd-resolved
+    let script = r#"
+        mount -t tmpfs tmpfs /etc && ln -s "$1" /etc/resolv.conf || exit 77
+        "$0" --resolv-conf "$2" sh -c 'test ! -L /etc/resolv.conf && cat /etc/resolv.conf'
+    "#;
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "sh", "-c", script])
+        .arg(env!("CARGO_BIN_EXE_nix-user-chroot"))
+        .args([&resolv_conf, &tempdir])
+        .output();
+    let output = match output {
+        Ok(output) if output.status.code() != Some(77) => output,
+        _ => {
+            eprintln!("skipping, cannot set up a symlinked /etc/resolv.conf");
+            return;
+        }
+    };
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "nameserver 192.0.2.1\n"
+    );
+}
+
+#[test]
+fn excluded_paths_are_absent() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--exclude", "/etc/hostname"], &tempdir)
+        .args(["sh", "-c", "test ! -e /etc/hostname && test -e /etc/passwd"])
+        .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn excluded_directories_are_absent_with_their_children() {
+    let tempdir = TempPath::dir("nix");
+    let exclude = tempdir.sibling("excluded");
+    fs::create_dir_all(exclude.join("sub")).unwrap();
+    fs::write(exclude.join("sub/file"), "").unwrap();
+    // an exclude below another one must not bring back its parents
+    let nested = exclude.join("sub/other");
+
+    let script = format!(
+        "test ! -e {0} && test ! -e {0}/sub/file && test -d {1}",
+        exclude.display(),
+        tempdir.display()
+    );
+    let output = nix_user_chroot(
+        &[
+            "--exclude",
+            exclude.to_str().unwrap(),
+            "--exclude",
+            nested.to_str().unwrap(),
+        ],
+        &tempdir,
+    )
+    .args(["sh", "-c", &script])
+    .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn only_included_roots_are_mirrored() {
+    let tempdir = TempPath::dir("nix");
+
+    let mut options = Vec::new();
+    for name in ["bin", "lib", "lib64", "proc", "usr"] {
+        options.extend(["--include-root", name]);
+    }
+    // the working directory has to exist inside as well
+    let output = nix_user_chroot(&options, &tempdir)
+        .current_dir("/")
+        .args(["sh", "-c", "test -d /usr && test ! -e /etc && test -d /nix"])
+        .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn workdir_and_missing_cwd() {
+    let tempdir = TempPath::dir("nix");
+    let cwd = TempPath::dir("cwd");
+
+    let workdir = nix_user_chroot(&["--workdir", "/etc"], &tempdir)
+        .arg("pwd")
+        .output();
+    let fallback = nix_user_chroot(&["--exclude", cwd.to_str().unwrap()], &tempdir)
+        .current_dir(&cwd)
+        .arg("pwd")
+        .output();
+    let workdir = workdir.unwrap();
+    assert!(workdir.status.success());
+    assert_eq!(String::from_utf8_lossy(&workdir.stdout), "/etc\n");
+    let fallback = fallback.unwrap();
+    assert!(fallback.status.success());
+    assert_eq!(String::from_utf8_lossy(&fallback.stdout), "/\n");
+    assert!(String::from_utf8_lossy(&fallback.stderr).contains("starting in /"));
+}
+
+#[test]
+fn home_is_set_and_created() {
+    let tempdir = TempPath::dir("nix");
+    let hosthome = TempPath::dir("home");
+    // only exists inside the chroot
+    let missing = tempdir.sibling("sandbox");
+    let home = missing.join("home");
+
+    let created = nix_user_chroot(&["--home", home.to_str().unwrap()], &tempdir)
+        .args(["sh", "-c", "test \"$HOME\" = \"$0\" && touch ~/file"])
+        .arg(&home)
+        .status();
+    let mirrored = nix_user_chroot(&["--home", hosthome.to_str().unwrap()], &tempdir)
+        .args(["sh", "-c", "touch ~/file"])
+        .status();
+    let written = hosthome.join("file").exists();
+    assert!(created.unwrap().success());
+    assert!(mirrored.unwrap().success());
+    assert!(written);
+    assert!(!missing.exists());
+}
+
+#[test]
+fn shell_is_started_without_command() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--shell", "sh"], &tempdir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            child.stdin.take().unwrap().write_all(b"echo $0\n")?;
+            child.wait_with_output()
+        });
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "sh");
+}
+
+#[test]
+fn missing_command_is_explained() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--env", "PATH=/nowhere"], &tempdir)
+        .arg("does-not-exist")
+        .output();
+    let output = output.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("does-not-exist was not found in PATH inside the chroot: /nowhere"));
+}
+
+#[test]
+fn runtime_dir_is_bind_mounted() {
+    let tempdir = TempPath::dir("nix");
+    let runtime_dir = TempPath::dir("runtime");
+    fs::write(runtime_dir.join("bus"), "").unwrap();
+
+    // /tmp is not mirrored, so only the explicit mount brings it along
+    let mut options = vec!["--runtime-dir", "--clearenv"];
+    for name in ["bin", "lib", "lib64", "usr"] {
+        options.extend(["--include-root", name]);
+    }
+    let script = format!(
+        "test -e \"$XDG_RUNTIME_DIR/bus\" && test ! -e {}",
+        tempdir.display()
+    );
+    let output = nix_user_chroot(&options, &tempdir)
+        .env("XDG_RUNTIME_DIR", &runtime_dir)
+        .current_dir("/")
+        .args(["sh", "-c", &script])
+        .status();
+    assert!(output.unwrap().success());
+}
+
+#[test]
+fn umask_is_set() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(&["--umask", "027"], &tempdir)
+        .args(["sh", "-c", "umask"])
+        .output();
+    let invalid = nix_user_chroot(&["--umask", "1000"], &tempdir)
+        .arg("true")
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "0027");
+    assert!(!invalid.unwrap().status.success());
+}
+
+#[test]
+fn env_overrides_defaults() {
+    let tempdir = TempPath::dir("nix");
+
+    let output = nix_user_chroot(
+        &["--env", "FOO=bar", "--env", "NIX_CONF_DIR=/etc/nix"],
+        &tempdir,
+    )
+    .args(["sh", "-c", "echo $FOO $NIX_CONF_DIR"])
+    .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "bar /etc/nix"
+    );
+}
+
+#[test]
+fn signals_are_forwarded_to_child() {
+    let tempdir = TempPath::dir("nix");
+    let ready = tempdir.sibling("ready");
+
+    let script = format!(
+        "trap 'exit 7' TERM; touch {}; while true; do sleep 0.1; done",
+        ready.display()
+    );
+    let mut child = nix_user_chroot(&[], &tempdir)
+        .args(["sh", "-c", &script])
+        .spawn()
+        .unwrap();
+    while !ready.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    let status = child.wait();
+    assert_eq!(status.unwrap().code(), Some(7));
+}
+
+#[test]
+fn signal_of_child_is_reraised() {
+    let tempdir = TempPath::dir("nix");
+
+    let status = nix_user_chroot(&[], &tempdir)
+        .args(["sh", "-c", "kill -TERM $$"])
+        .status();
+    let status = status.unwrap();
+    assert_eq!(status.code(), None);
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+}
+
+#[test]
+fn tempdir_is_removed_when_killed_by_signal() {
+    let tempdir = TempPath::dir("nix");
+    let tmpdir = tempdir.sibling("tmp");
+    fs::create_dir(&tmpdir).unwrap();
+
+    // SIGKILL cannot be caught, so re-raising it ends us for sure
+    let status = nix_user_chroot(&["--tmpdir", tmpdir.to_str().unwrap()], &tempdir)
+        .args(["sh", "-c", "kill -KILL $$"])
+        .status();
+    let leftover = fs::read_dir(&tmpdir).unwrap().count();
+    assert_eq!(status.unwrap().signal(), Some(libc::SIGKILL));
+    assert_eq!(leftover, 0);
+}
+
+#[test]
+fn dangling_symlinks_are_mirrored() {
+    let tempdir = TempPath::dir("nix");
+    let link = tempdir.sibling("dangling");
+    symlink("/does/not/exist", &link).unwrap();
+
+    // excluding a sibling makes /tmp get mirrored entry by entry
+    let exclude = tempdir.sibling("excluded");
+    let script = format!("readlink {}", link.display());
+    let output = nix_user_chroot(&["--exclude", exclude.to_str().unwrap()], &tempdir)
+        .args(["sh", "-c", &script])
+        .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "/does/not/exist"
+    );
+}
+
+#[test]
+fn symlink_names_are_kept() {
+    let tempdir = TempPath::dir("nix");
+    // like a multi-call binary, it behaves depending on the name it is
+    // called by
+    let script = tempdir.sibling("multi-call");
+    fs::write(&script, "#!/bin/sh\necho ${0##*/}\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let link = tempdir.sibling("cargo");
+    symlink(&script, &link).unwrap();
+
+    // an explicit mount of a symlink gets the name of its destination,
+    // while a mirrored symlink keeps its own name. Excluding a sibling
+    // makes /tmp get mirrored entry by entry.
+    let bind = format!("{}:/opt/bin/rustc", link.display());
+    let exclude = tempdir.sibling("excluded");
+    let command = format!("/opt/bin/rustc && {}", link.display());
+    let output = nix_user_chroot(
+        &["--bind", &bind, "--exclude", exclude.to_str().unwrap()],
+        &tempdir,
+    )
+    .args(["sh", "-c", &command])
+    .output();
+    let output = output.unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("rustc\n{}\n", link.file_name().unwrap().to_string_lossy())
+    );
+}